@@ -0,0 +1,193 @@
+use std::collections::BTreeMap;
+
+use chrono::{DateTime, FixedOffset, NaiveDate, NaiveDateTime, Utc};
+use serde::Serialize;
+
+pub(crate) const TIMESTAMP_BIND_FORMAT: &str = "%Y-%m-%d %H:%M:%S%.9f";
+pub(crate) const TIMESTAMP_TZ_BIND_FORMAT: &str = "%Y-%m-%d %H:%M:%S%.9f %:z";
+
+/// A value for a `?` placeholder, sent in the `bindings` of a statement request.
+#[derive(Clone, Debug, PartialEq)]
+pub enum SnowflakeBind {
+    /// A SQL NULL, bound as `TEXT` so Snowflake can coerce it to the column's type.
+    Null,
+    Text(String),
+    /// An exact `NUMBER`: `value / 10^scale`.
+    Number {
+        value: i128,
+        scale: u32,
+    },
+    Real(f64),
+    Boolean(bool),
+    Date(NaiveDate),
+    TimestampNtz(NaiveDateTime),
+    TimestampLtz(DateTime<Utc>),
+    TimestampTz(DateTime<FixedOffset>),
+}
+
+#[derive(Debug, Serialize)]
+pub(crate) struct Binding {
+    r#type: &'static str,
+    value: Option<String>,
+}
+
+impl SnowflakeBind {
+    fn to_binding(&self) -> Binding {
+        let (r#type, value) = match self {
+            SnowflakeBind::Null => ("TEXT", None),
+            SnowflakeBind::Text(text) => ("TEXT", Some(text.clone())),
+            SnowflakeBind::Number { value, scale } => ("FIXED", Some(format_fixed(*value, *scale))),
+            SnowflakeBind::Real(real) => ("REAL", Some(real.to_string())),
+            SnowflakeBind::Boolean(boolean) => ("BOOLEAN", Some(boolean.to_string())),
+            SnowflakeBind::Date(date) => ("DATE", Some(date.format("%Y-%m-%d").to_string())),
+            SnowflakeBind::TimestampNtz(ts) => (
+                "TIMESTAMP_NTZ",
+                Some(ts.format(TIMESTAMP_BIND_FORMAT).to_string()),
+            ),
+            SnowflakeBind::TimestampLtz(ts) => (
+                "TIMESTAMP_LTZ",
+                Some(ts.format(TIMESTAMP_TZ_BIND_FORMAT).to_string()),
+            ),
+            SnowflakeBind::TimestampTz(ts) => (
+                "TIMESTAMP_TZ",
+                Some(ts.format(TIMESTAMP_TZ_BIND_FORMAT).to_string()),
+            ),
+        };
+        Binding { r#type, value }
+    }
+}
+
+/// `value / 10^scale` with exactly `scale` fractional digits, e.g. `-150` at scale 2 is
+/// `-1.50`.
+pub(crate) fn format_fixed(value: i128, scale: u32) -> String {
+    let sign = if value < 0 { "-" } else { "" };
+    let (integer, fraction) = split_fixed(value.unsigned_abs(), scale);
+    if fraction.is_empty() {
+        format!("{sign}{integer}")
+    } else {
+        format!("{sign}{integer}.{fraction}")
+    }
+}
+
+/// The integer and fractional digits of `abs / 10^scale`, the integer part being at least `0`.
+pub(crate) fn split_fixed(abs: u128, scale: u32) -> (String, String) {
+    let digits = format!("{abs:0>width$}", width = scale as usize + 1);
+    let (integer, fraction) = digits.split_at(digits.len() - scale as usize);
+    (integer.to_string(), fraction.to_string())
+}
+
+/// The `bindings` object of a statement request, keyed by 1-based position.
+pub(crate) fn bindings(binds: &[SnowflakeBind]) -> BTreeMap<String, Binding> {
+    binds
+        .iter()
+        .enumerate()
+        .map(|(idx, bind)| ((idx + 1).to_string(), bind.to_binding()))
+        .collect()
+}
+
+/// Counts the `?` placeholders in `sql`, skipping string literals, quoted identifiers and
+/// comments.
+pub(crate) fn count_placeholders(sql: &str) -> usize {
+    let mut count = 0;
+    let mut chars = sql.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '?' => count += 1,
+            '\'' | '"' => {
+                while let Some(next) = chars.next() {
+                    if next == '\\' && c == '\'' {
+                        chars.next();
+                    } else if next == c {
+                        // a doubled quote is an escaped quote, not the end of the literal
+                        if chars.peek() == Some(&c) {
+                            chars.next();
+                        } else {
+                            break;
+                        }
+                    }
+                }
+            }
+            '$' if chars.peek() == Some(&'$') => {
+                chars.next();
+                while let Some(next) = chars.next() {
+                    if next == '$' && chars.peek() == Some(&'$') {
+                        chars.next();
+                        break;
+                    }
+                }
+            }
+            '-' if chars.peek() == Some(&'-') => {
+                for next in chars.by_ref() {
+                    if next == '\n' {
+                        break;
+                    }
+                }
+            }
+            '/' if chars.peek() == Some(&'*') => {
+                chars.next();
+                while let Some(next) = chars.next() {
+                    if next == '*' && chars.peek() == Some(&'/') {
+                        chars.next();
+                        break;
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+    count
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn counts_placeholders_outside_literals_and_comments() {
+        let cases = [
+            ("SELECT 1", 0),
+            ("SELECT * FROM t WHERE a = ? AND b = ?", 2),
+            ("SELECT '?', 'it''s ?', 'a\\'?' WHERE c = ?", 1),
+            (r#"SELECT "col?" FROM t WHERE d = ?"#, 1),
+            ("SELECT $$ ? $$, ? -- trailing ?\n, ? /* ? */", 2),
+        ];
+        for (sql, expected) in cases {
+            assert_eq!(count_placeholders(sql), expected, "{sql}");
+        }
+    }
+
+    #[test]
+    fn serializes_positional_bindings() {
+        let ts = NaiveDate::from_ymd_opt(2021, 7, 1)
+            .unwrap()
+            .and_hms_milli_opt(12, 30, 0, 5)
+            .unwrap();
+        let binds = [
+            SnowflakeBind::Text("peerdb".to_string()),
+            SnowflakeBind::Number {
+                value: -1205,
+                scale: 3,
+            },
+            SnowflakeBind::Boolean(true),
+            SnowflakeBind::TimestampNtz(ts),
+            SnowflakeBind::Null,
+        ];
+        assert_eq!(
+            serde_json::to_value(bindings(&binds)).unwrap(),
+            serde_json::json!({
+                "1": { "type": "TEXT", "value": "peerdb" },
+                "2": { "type": "FIXED", "value": "-1.205" },
+                "3": { "type": "BOOLEAN", "value": "true" },
+                "4": { "type": "TIMESTAMP_NTZ", "value": "2021-07-01 12:30:00.005000000" },
+                "5": { "type": "TEXT", "value": null },
+            })
+        );
+    }
+
+    #[test]
+    fn formats_fixed_point_numbers() {
+        assert_eq!(format_fixed(42, 0), "42");
+        assert_eq!(format_fixed(5, 2), "0.05");
+        assert_eq!(format_fixed(-150, 2), "-1.50");
+    }
+}
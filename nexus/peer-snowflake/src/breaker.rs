@@ -0,0 +1,261 @@
+//! A circuit breaker that stops sending statements to a peer that keeps failing, so that each
+//! of them doesn't pay the full retry budget while e.g. its credentials are wrong.
+
+use std::{
+    sync::{Mutex, MutexGuard, PoisonError},
+    time::{Duration, Instant},
+};
+
+use crate::{ErrorClass, SnowflakeClient, SnowflakeError};
+
+/// When a [`SnowflakeClient`] opens its circuit, see [`SnowflakeClient::with_circuit_breaker`].
+#[derive(Clone, Debug)]
+pub struct CircuitBreakerPolicy {
+    /// Consecutive auth failures that open the circuit. These won't resolve by themselves, so
+    /// the default of 2 is lower.
+    pub auth_failure_threshold: u32,
+    /// Consecutive transient or fatal failures that open the circuit.
+    pub failure_threshold: u32,
+    /// How long an open circuit rejects statements before letting one through to probe the
+    /// peer.
+    pub cool_down: Duration,
+}
+
+impl Default for CircuitBreakerPolicy {
+    fn default() -> Self {
+        Self {
+            auth_failure_threshold: 2,
+            failure_threshold: 5,
+            cool_down: Duration::from_secs(30),
+        }
+    }
+}
+
+/// State of a client's circuit, see [`SnowflakeClient::circuit_state`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CircuitState {
+    /// Statements are sent as usual.
+    Closed,
+    /// Statements fail with [`SnowflakeError::CircuitOpen`] for `remaining`, after a run of
+    /// failures of `class`.
+    Open {
+        class: ErrorClass,
+        remaining: Duration,
+    },
+    /// The cool-down is over: the next statement probes the peer, closing the circuit if it
+    /// succeeds and opening it again if it fails.
+    HalfOpen { class: ErrorClass },
+}
+
+pub(crate) struct CircuitBreaker {
+    policy: CircuitBreakerPolicy,
+    state: Mutex<BreakerState>,
+}
+
+#[derive(Default)]
+struct BreakerState {
+    // consecutive failures, all of `class`
+    failures: u32,
+    class: Option<ErrorClass>,
+    // set while the circuit is open or half-open
+    opened_at: Option<Instant>,
+    // a probe that doesn't report back (e.g. because it was dropped) is replaced after another
+    // cool-down
+    probe_started: Option<Instant>,
+}
+
+impl CircuitBreaker {
+    pub(crate) fn new(policy: CircuitBreakerPolicy) -> Self {
+        Self {
+            policy,
+            state: Mutex::default(),
+        }
+    }
+
+    /// Lets a statement through, unless the circuit is open or another statement is probing.
+    pub(crate) fn admit(&self) -> Result<(), SnowflakeError> {
+        let mut state = self.lock();
+        let (Some(opened_at), Some(class)) = (state.opened_at, state.class) else {
+            return Ok(());
+        };
+        let waiting_since = state.probe_started.unwrap_or(opened_at);
+        match self.policy.cool_down.checked_sub(waiting_since.elapsed()) {
+            Some(retry_in) if !retry_in.is_zero() => {
+                Err(SnowflakeError::CircuitOpen { class, retry_in })
+            }
+            _ => {
+                state.probe_started = Some(Instant::now());
+                Ok(())
+            }
+        }
+    }
+
+    /// Counts the outcome of an admitted statement. Errors in the statement itself mean the
+    /// peer is answering, so they count as successes.
+    pub(crate) fn record(&self, res: Result<(), &SnowflakeError>) {
+        let class = match res.map_err(SnowflakeError::class) {
+            Ok(()) | Err(ErrorClass::UserError) => {
+                *self.lock() = BreakerState::default();
+                return;
+            }
+            Err(class) => class,
+        };
+        let mut state = self.lock();
+        if state.class == Some(class) {
+            state.failures += 1;
+        } else {
+            state.class = Some(class);
+            state.failures = 1;
+        }
+        let threshold = match class {
+            ErrorClass::AuthFailure => self.policy.auth_failure_threshold,
+            _ => self.policy.failure_threshold,
+        };
+        // a failed probe opens the circuit again, whatever its class; other statements failing
+        // while it is open were sent before it opened
+        let probe_failed = state.probe_started.is_some();
+        if probe_failed || (state.opened_at.is_none() && state.failures >= threshold) {
+            state.opened_at = Some(Instant::now());
+            state.probe_started = None;
+        }
+    }
+
+    pub(crate) fn state(&self) -> CircuitState {
+        let state = self.lock();
+        let (Some(opened_at), Some(class)) = (state.opened_at, state.class) else {
+            return CircuitState::Closed;
+        };
+        match self.policy.cool_down.checked_sub(opened_at.elapsed()) {
+            Some(remaining) if !remaining.is_zero() && state.probe_started.is_none() => {
+                CircuitState::Open { class, remaining }
+            }
+            _ => CircuitState::HalfOpen { class },
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, BreakerState> {
+        self.state.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+impl SnowflakeClient {
+    /// State of the circuit breaker set with [`SnowflakeClient::with_circuit_breaker`],
+    /// [`CircuitState::Closed`] without one.
+    pub fn circuit_state(&self) -> CircuitState {
+        self.circuit_breaker()
+            .map_or(CircuitState::Closed, CircuitBreaker::state)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use reqwest::StatusCode;
+
+    use super::*;
+    use crate::mock::{MockSnowflakeServer, MockStatement};
+
+    fn failure(status: StatusCode) -> SnowflakeError {
+        SnowflakeError::from_response(status, String::new())
+    }
+
+    fn breaker(cool_down: Duration) -> CircuitBreaker {
+        CircuitBreaker::new(CircuitBreakerPolicy {
+            cool_down,
+            ..Default::default()
+        })
+    }
+
+    #[test]
+    fn opens_after_consecutive_failures_of_a_class() {
+        let breaker = breaker(Duration::from_secs(60));
+        let unavailable = failure(StatusCode::SERVICE_UNAVAILABLE);
+        for _ in 0..4 {
+            breaker.record(Err(&unavailable));
+        }
+        // a different class starts the count over
+        breaker.record(Err(&failure(StatusCode::UNAUTHORIZED)));
+        breaker.record(Err(&unavailable));
+        assert_eq!(breaker.state(), CircuitState::Closed);
+        assert!(breaker.admit().is_ok());
+
+        breaker.record(Err(&failure(StatusCode::UNAUTHORIZED)));
+        breaker.record(Err(&failure(StatusCode::FORBIDDEN)));
+        assert!(matches!(
+            breaker.state(),
+            CircuitState::Open {
+                class: ErrorClass::AuthFailure,
+                ..
+            }
+        ));
+        let err = breaker.admit().unwrap_err();
+        assert!(
+            matches!(
+                err,
+                SnowflakeError::CircuitOpen {
+                    class: ErrorClass::AuthFailure,
+                    ..
+                }
+            ),
+            "{err:?}"
+        );
+        assert_eq!(err.class(), ErrorClass::AuthFailure);
+    }
+
+    #[test]
+    fn user_errors_keep_the_circuit_closed() {
+        let breaker = breaker(Duration::from_secs(60));
+        let unauthorized = failure(StatusCode::UNAUTHORIZED);
+        breaker.record(Err(&unauthorized));
+        breaker.record(Err(&failure(StatusCode::UNPROCESSABLE_ENTITY)));
+        breaker.record(Err(&unauthorized));
+        assert_eq!(breaker.state(), CircuitState::Closed);
+    }
+
+    #[test]
+    fn probes_once_the_cool_down_is_over() {
+        let breaker = breaker(Duration::from_millis(20));
+        let unauthorized = failure(StatusCode::UNAUTHORIZED);
+        breaker.record(Err(&unauthorized));
+        breaker.record(Err(&unauthorized));
+        assert!(breaker.admit().is_err());
+
+        std::thread::sleep(Duration::from_millis(30));
+        assert_eq!(
+            breaker.state(),
+            CircuitState::HalfOpen {
+                class: ErrorClass::AuthFailure
+            }
+        );
+        breaker.admit().unwrap();
+        // only one probe at a time
+        assert!(breaker.admit().is_err());
+        breaker.record(Err(&unauthorized));
+        assert!(matches!(breaker.state(), CircuitState::Open { .. }));
+
+        std::thread::sleep(Duration::from_millis(30));
+        breaker.admit().unwrap();
+        breaker.record(Ok(()));
+        assert_eq!(breaker.state(), CircuitState::Closed);
+    }
+
+    #[tokio::test]
+    async fn short_circuits_statements_while_open() {
+        let server = MockSnowflakeServer::start().await;
+        server.mount_statement(MockStatement::new("handle-1")).await;
+        server.fail_next("POST", "/api/v2/statements", 401, 2).await;
+        let client = server
+            .client()
+            .with_circuit_breaker(CircuitBreakerPolicy::default());
+
+        for _ in 0..2 {
+            client.execute("SELECT 1").await.unwrap_err();
+        }
+        let err = client.execute("SELECT 1").await.unwrap_err();
+        assert!(matches!(err, SnowflakeError::CircuitOpen { .. }), "{err:?}");
+        assert_eq!(server.requests_to("/api/v2/statements").await.len(), 2);
+
+        let health = client.health(false).await;
+        assert!(matches!(health.circuit, CircuitState::Open { .. }));
+        assert!(!health.is_healthy());
+    }
+}
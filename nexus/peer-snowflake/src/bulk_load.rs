@@ -0,0 +1,751 @@
+//! Loading rows into a table through a stage and `COPY INTO`.
+//!
+//! Rows, or Arrow batches, are written to CSV files that are `PUT` on the stage under a prefix
+//! of their own as each fills up, then a single `COPY INTO` loads them all. Files that reach the
+//! stage some other way, e.g. uploaded to the bucket of an external stage, are loaded the same
+//! way. Either way the loader removes the files afterwards, whether or not the copy succeeded.
+
+use std::{borrow::Cow, fmt, io::Write, pin::pin, sync::Arc};
+
+use flate2::{Compression, write::GzEncoder};
+use futures::{Stream, StreamExt};
+use tracing::{info, warn};
+use uuid::Uuid;
+
+use crate::{
+    SnowflakeClient, SnowflakeError, SnowflakeResultSet, SnowflakeValue,
+    bind::{TIMESTAMP_BIND_FORMAT, TIMESTAMP_TZ_BIND_FORMAT, format_fixed},
+    file_transfer::FileTransferSession,
+};
+
+// of CSV before compression, as each file is held in memory until it is uploaded
+const DEFAULT_MAX_FILE_SIZE: usize = 64 << 20;
+const TIME_FORMAT: &str = "%H:%M:%S%.9f";
+
+/// Format of the staged files.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum StagedFileFormat {
+    #[default]
+    Csv,
+    Parquet,
+}
+
+/// Compression of the staged files. `Auto` lets Snowflake detect it from the file extension.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum StagedFileCompression {
+    #[default]
+    Auto,
+    None,
+    Gzip,
+    Zstd,
+    Snappy,
+}
+
+impl fmt::Display for StagedFileFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            StagedFileFormat::Csv => "CSV",
+            StagedFileFormat::Parquet => "PARQUET",
+        })
+    }
+}
+
+impl fmt::Display for StagedFileCompression {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            StagedFileCompression::Auto => "AUTO",
+            StagedFileCompression::None => "NONE",
+            StagedFileCompression::Gzip => "GZIP",
+            StagedFileCompression::Zstd => "ZSTD",
+            StagedFileCompression::Snappy => "SNAPPY",
+        })
+    }
+}
+
+/// Rows of one `COPY INTO`, summed over its files.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct LoadSummary {
+    pub rows_loaded: u64,
+    /// Rows skipped because they failed to parse or convert, see `first_error` in the
+    /// `COPY INTO` output for why.
+    pub rows_rejected: u64,
+}
+
+/// Loads rows into tables through a stage, e.g. the batches of a mirror.
+pub struct SnowflakeBulkLoader {
+    client: Arc<SnowflakeClient>,
+    stage: String,
+    file_format: StagedFileFormat,
+    compression: StagedFileCompression,
+    max_file_size: usize,
+}
+
+impl SnowflakeBulkLoader {
+    /// A loader of CSV files through `stage`, a stage name without the leading `@`.
+    pub fn new(client: Arc<SnowflakeClient>, stage: impl Into<String>) -> Self {
+        Self {
+            client,
+            stage: stage.into(),
+            file_format: StagedFileFormat::default(),
+            compression: StagedFileCompression::default(),
+            max_file_size: DEFAULT_MAX_FILE_SIZE,
+        }
+    }
+
+    pub fn with_file_format(mut self, file_format: StagedFileFormat) -> Self {
+        self.file_format = file_format;
+        self
+    }
+
+    pub fn with_compression(mut self, compression: StagedFileCompression) -> Self {
+        self.compression = compression;
+        self
+    }
+
+    /// Starts a new file once the one being written by [`SnowflakeBulkLoader::load_rows`] or
+    /// `load_arrow` has `max_file_size` bytes of CSV, 64 MiB by default. Each file is held in
+    /// memory until it is uploaded.
+    pub fn with_max_file_size(mut self, max_file_size: usize) -> Self {
+        self.max_file_size = max_file_size;
+        self
+    }
+
+    fn copy_sql(&self, table: &str, prefix: &str, file_format: &str) -> String {
+        // bad rows are skipped and counted rather than failing the whole batch
+        format!(
+            "COPY INTO {table} FROM @{}/{prefix} FILE_FORMAT = ({file_format}) \
+             ON_ERROR = CONTINUE",
+            self.stage
+        )
+    }
+
+    /// Copies the files under `prefix` of the stage into `table`, then removes them from the
+    /// stage. `table` is used as is, so it must already be quoted if needed.
+    #[tracing::instrument(name = "peer_sflake::bulk_load", skip_all)]
+    pub async fn load(&self, table: &str, prefix: &str) -> Result<LoadSummary, SnowflakeError> {
+        let file_format = format!(
+            "TYPE = {} COMPRESSION = {}",
+            self.file_format, self.compression
+        );
+        self.copy_and_remove(table, prefix, Ok(()), &file_format)
+            .await
+    }
+
+    /// Writes `rows` to CSV files on the stage, then copies them into `table` and removes them
+    /// as [`SnowflakeBulkLoader::load`] does. Each row has the table's columns, in order.
+    ///
+    /// The files are gzipped, or left uncompressed with [`StagedFileCompression::None`]; other
+    /// formats and compressions can only be loaded from files already on the stage.
+    #[tracing::instrument(name = "peer_sflake::bulk_load_rows", skip_all)]
+    pub async fn load_rows<S>(&self, table: &str, rows: S) -> Result<LoadSummary, SnowflakeError>
+    where
+        S: Stream<Item = Result<Vec<SnowflakeValue>, SnowflakeError>>,
+    {
+        let mut files = StagedFiles::new(self)?;
+        let mut rows = pin!(rows);
+        let written = async {
+            while let Some(row) = rows.next().await {
+                files.write_row(&row?);
+                files.upload_if_full().await?;
+            }
+            Ok(())
+        }
+        .await;
+        self.copy_staged(table, files, written).await
+    }
+
+    /// Like [`SnowflakeBulkLoader::load_rows`], with the rows of Arrow `batches`, whose columns
+    /// are the table's, in order.
+    #[cfg(feature = "arrow")]
+    #[tracing::instrument(name = "peer_sflake::bulk_load_arrow", skip_all)]
+    pub async fn load_arrow<S>(
+        &self,
+        table: &str,
+        batches: S,
+    ) -> Result<LoadSummary, SnowflakeError>
+    where
+        S: Stream<Item = Result<arrow::record_batch::RecordBatch, SnowflakeError>>,
+    {
+        let mut files = StagedFiles::new(self)?;
+        let mut batches = pin!(batches);
+        let written = async {
+            while let Some(batch) = batches.next().await {
+                files.write_batch(&batch?)?;
+                files.upload_if_full().await?;
+            }
+            Ok(())
+        }
+        .await;
+        self.copy_staged(table, files, written).await
+    }
+
+    async fn copy_staged(
+        &self,
+        table: &str,
+        files: StagedFiles<'_>,
+        written: Result<(), SnowflakeError>,
+    ) -> Result<LoadSummary, SnowflakeError> {
+        let prefix = files.prefix.clone();
+        let file_format = files.file_format();
+        let (staged, res) = files.finish(written).await;
+        if staged == 0 {
+            return res.map(|()| LoadSummary::default());
+        }
+        self.copy_and_remove(table, &prefix, res, &file_format)
+            .await
+    }
+
+    // `staged` is the outcome of getting the files on the stage, which are removed either way
+    async fn copy_and_remove(
+        &self,
+        table: &str,
+        prefix: &str,
+        staged: Result<(), SnowflakeError>,
+        file_format: &str,
+    ) -> Result<LoadSummary, SnowflakeError> {
+        let res = match staged {
+            Ok(()) => {
+                let copy = self.copy_sql(table, prefix, file_format);
+                self.client.execute(&copy).await
+            }
+            Err(err) => Err(err),
+        };
+        let remove = format!("REMOVE @{}/{prefix}", self.stage);
+        if let Err(err) = self.client.execute(&remove).await {
+            warn!(
+                "Failed to remove staged files @{}/{}: {}",
+                self.stage, prefix, err
+            );
+        }
+        let summary = load_summary(&res?);
+        info!(
+            "Loaded {} rows into {}, rejected {}",
+            summary.rows_loaded, table, summary.rows_rejected
+        );
+        Ok(summary)
+    }
+}
+
+/// The CSV files of one load, each uploaded once it reaches the loader's `max_file_size`.
+struct StagedFiles<'a> {
+    loader: &'a SnowflakeBulkLoader,
+    prefix: String,
+    gzip: bool,
+    session: Option<FileTransferSession<'a>>,
+    csv: Vec<u8>,
+    // put on the stage, or tried to
+    staged: usize,
+}
+
+impl<'a> StagedFiles<'a> {
+    fn new(loader: &'a SnowflakeBulkLoader) -> Result<Self, SnowflakeError> {
+        let unsupported = |what: String| {
+            Err(SnowflakeError::FileTransfer(format!(
+                "can't write {what} files, only CSV, gzipped or not"
+            )))
+        };
+        if loader.file_format != StagedFileFormat::Csv {
+            return unsupported(loader.file_format.to_string());
+        }
+        let gzip = match loader.compression {
+            StagedFileCompression::Auto | StagedFileCompression::Gzip => true,
+            StagedFileCompression::None => false,
+            compression => return unsupported(compression.to_string()),
+        };
+        Ok(Self {
+            loader,
+            // files of other loads through the stage are left alone
+            prefix: format!("peerdb_load_{}", Uuid::new_v4().simple()),
+            gzip,
+            session: None,
+            csv: Vec::new(),
+            staged: 0,
+        })
+    }
+
+    fn file_format(&self) -> String {
+        // quoted empty strings stay empty strings, unquoted empty fields are NULL
+        format!(
+            "TYPE = CSV COMPRESSION = {} FIELD_OPTIONALLY_ENCLOSED_BY = '\"' NULL_IF = () \
+             BINARY_FORMAT = HEX",
+            if self.gzip { "GZIP" } else { "NONE" }
+        )
+    }
+
+    fn write_row(&mut self, row: &[SnowflakeValue]) {
+        for (idx, value) in row.iter().enumerate() {
+            if idx > 0 {
+                self.csv.push(b',');
+            }
+            if let Some(text) = csv_text(value) {
+                write_quoted(&mut self.csv, &text);
+            }
+        }
+        self.csv.push(b'\n');
+    }
+
+    #[cfg(feature = "arrow")]
+    fn write_batch(
+        &mut self,
+        batch: &arrow::record_batch::RecordBatch,
+    ) -> Result<(), SnowflakeError> {
+        use arrow::{
+            array::Array,
+            util::display::{ArrayFormatter, FormatOptions},
+        };
+
+        let options = FormatOptions::new()
+            .with_date_format(Some("%Y-%m-%d"))
+            .with_datetime_format(Some(TIMESTAMP_BIND_FORMAT))
+            .with_timestamp_format(Some(TIMESTAMP_BIND_FORMAT))
+            .with_timestamp_tz_format(Some(TIMESTAMP_TZ_BIND_FORMAT))
+            .with_time_format(Some(TIME_FORMAT));
+        let formatters = batch
+            .columns()
+            .iter()
+            .map(|column| ArrayFormatter::try_new(column.as_ref(), &options))
+            .collect::<Result<Vec<_>, _>>()?;
+        for row in 0..batch.num_rows() {
+            for (idx, (column, formatter)) in batch.columns().iter().zip(&formatters).enumerate() {
+                if idx > 0 {
+                    self.csv.push(b',');
+                }
+                if !column.is_null(row) {
+                    write_quoted(&mut self.csv, &formatter.value(row).try_to_string()?);
+                }
+            }
+            self.csv.push(b'\n');
+        }
+        Ok(())
+    }
+
+    async fn upload_if_full(&mut self) -> Result<(), SnowflakeError> {
+        if self.csv.len() < self.loader.max_file_size {
+            return Ok(());
+        }
+        self.upload().await
+    }
+
+    async fn upload(&mut self) -> Result<(), SnowflakeError> {
+        if self.csv.is_empty() {
+            return Ok(());
+        }
+        let csv = std::mem::take(&mut self.csv);
+        let (file, file_name, source_compression) = if self.gzip {
+            let mut gzip = GzEncoder::new(Vec::new(), Compression::default());
+            gzip.write_all(&csv)?;
+            (
+                gzip.finish()?,
+                format!("part_{:05}.csv.gz", self.staged),
+                "GZIP",
+            )
+        } else {
+            (csv, format!("part_{:05}.csv", self.staged), "NONE")
+        };
+        let session = match &mut self.session {
+            Some(session) => session,
+            None => self
+                .session
+                .insert(self.loader.client.file_transfer_session().await?),
+        };
+        self.staged += 1;
+        let stage_path = format!("{}/{}/", self.loader.stage, self.prefix);
+        session
+            .put(&stage_path, &file_name, source_compression, file)
+            .await
+    }
+
+    /// Uploads the last file unless `written` failed, and ends the session. Returns how many
+    /// files may be on the stage, and the first error.
+    async fn finish(
+        mut self,
+        written: Result<(), SnowflakeError>,
+    ) -> (usize, Result<(), SnowflakeError>) {
+        let res = match written {
+            Ok(()) => self.upload().await,
+            Err(err) => Err(err),
+        };
+        if let Some(session) = self.session.take() {
+            session.close().await;
+        }
+        (self.staged, res)
+    }
+}
+
+/// `value` as Snowflake parses it from CSV by default, `None` for NULL.
+fn csv_text(value: &SnowflakeValue) -> Option<Cow<'_, str>> {
+    let text = match value {
+        SnowflakeValue::Null => return None,
+        SnowflakeValue::Number { value, scale } => format_fixed(*value, *scale).into(),
+        // NaN, inf and -inf as Snowflake spells them
+        SnowflakeValue::Real(real) => real.to_string().into(),
+        SnowflakeValue::Text(text) | SnowflakeValue::Geo(text) => text.as_str().into(),
+        SnowflakeValue::Boolean(boolean) => if *boolean { "TRUE" } else { "FALSE" }.into(),
+        SnowflakeValue::Date(date) => date.format("%Y-%m-%d").to_string().into(),
+        SnowflakeValue::Time(time) => time.format(TIME_FORMAT).to_string().into(),
+        SnowflakeValue::TimestampNtz(ts) => ts.format(TIMESTAMP_BIND_FORMAT).to_string().into(),
+        SnowflakeValue::TimestampLtz(ts) => ts.format(TIMESTAMP_TZ_BIND_FORMAT).to_string().into(),
+        SnowflakeValue::TimestampTz(ts) => ts.format(TIMESTAMP_TZ_BIND_FORMAT).to_string().into(),
+        SnowflakeValue::Binary(binary) => hex::encode_upper(binary).into(),
+        SnowflakeValue::Variant(variant) => variant.to_string().into(),
+    };
+    Some(text)
+}
+
+// every value is quoted, so that none needs to be checked for separators
+fn write_quoted(csv: &mut Vec<u8>, text: &str) {
+    csv.push(b'"');
+    for byte in text.bytes() {
+        if byte == b'"' {
+            csv.push(b'"');
+        }
+        csv.push(byte);
+    }
+    csv.push(b'"');
+}
+
+// COPY INTO returns one row per file, or a single status row when there were no files
+fn load_summary(result_set: &SnowflakeResultSet) -> LoadSummary {
+    let column = |name: &str| {
+        result_set
+            .column_names()
+            .position(|column| column.eq_ignore_ascii_case(name))
+    };
+    let (rows_loaded, errors_seen) = (column("rows_loaded"), column("errors_seen"));
+    let sum = |idx: Option<usize>| -> u64 {
+        let Some(idx) = idx else {
+            return 0;
+        };
+        result_set
+            .rows()
+            .iter()
+            .filter_map(|row| row.get(idx)?.as_deref()?.parse::<u64>().ok())
+            .sum()
+    };
+    LoadSummary {
+        rows_loaded: sum(rows_loaded),
+        rows_rejected: sum(errors_seen),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+
+    use chrono::NaiveDate;
+    use flate2::read::GzDecoder;
+    use serde_json::json;
+
+    use super::*;
+    use crate::mock::{MockSnowflakeServer, MockStatement};
+
+    fn gunzip(file: &[u8]) -> String {
+        let mut csv = String::new();
+        GzDecoder::new(file).read_to_string(&mut csv).unwrap();
+        csv
+    }
+
+    fn rows(
+        rows: Vec<Vec<SnowflakeValue>>,
+    ) -> impl Stream<Item = Result<Vec<SnowflakeValue>, SnowflakeError>> {
+        futures::stream::iter(rows.into_iter().map(Ok))
+    }
+
+    fn copy_result(files: &[(&str, u64, u64)]) -> MockStatement {
+        let rows = files
+            .iter()
+            .map(|(file, loaded, errors)| {
+                vec![
+                    Some(file.to_string()),
+                    Some("LOADED".to_string()),
+                    Some(loaded.to_string()),
+                    Some(errors.to_string()),
+                ]
+            })
+            .collect();
+        MockStatement::new("handle-1")
+            .column("file", "text")
+            .column("status", "text")
+            .column("rows_loaded", "fixed")
+            .column("errors_seen", "fixed")
+            .partition(rows)
+    }
+
+    async fn statements(server: &MockSnowflakeServer) -> Vec<String> {
+        server
+            .requests_to("/api/v2/statements")
+            .await
+            .iter()
+            .map(|request| {
+                let body: serde_json::Value = request.body_json().unwrap();
+                body["statement"].as_str().unwrap().to_string()
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn copies_and_removes_staged_files() {
+        let server = MockSnowflakeServer::start().await;
+        server
+            .mount_statement(copy_result(&[
+                ("batch_1/a.parquet", 100, 0),
+                ("batch_1/b.parquet", 40, 2),
+            ]))
+            .await;
+        let loader = SnowflakeBulkLoader::new(Arc::new(server.client()), "peerdb_stage")
+            .with_file_format(StagedFileFormat::Parquet)
+            .with_compression(StagedFileCompression::Snappy);
+
+        let summary = loader.load("\"public_users\"", "batch_1").await.unwrap();
+        assert_eq!(
+            summary,
+            LoadSummary {
+                rows_loaded: 140,
+                rows_rejected: 2
+            }
+        );
+        assert_eq!(
+            statements(&server).await,
+            [
+                "COPY INTO \"public_users\" FROM @peerdb_stage/batch_1 \
+                 FILE_FORMAT = (TYPE = PARQUET COMPRESSION = SNAPPY) ON_ERROR = CONTINUE",
+                "REMOVE @peerdb_stage/batch_1",
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn stages_rows_and_copies_them() {
+        let server = MockSnowflakeServer::start().await;
+        server.mount_stage(None).await;
+        server
+            .mount_statement(copy_result(&[("part_00000.csv.gz", 3, 0)]))
+            .await;
+        let loader = SnowflakeBulkLoader::new(Arc::new(server.client()), "peerdb_stage");
+
+        let summary = loader
+            .load_rows(
+                "\"public_users\"",
+                rows(vec![
+                    vec![
+                        SnowflakeValue::Number {
+                            value: -1250,
+                            scale: 2,
+                        },
+                        SnowflakeValue::Text("say \"hi\", twice".to_string()),
+                        SnowflakeValue::Null,
+                    ],
+                    vec![
+                        SnowflakeValue::Boolean(true),
+                        SnowflakeValue::Text(String::new()),
+                        SnowflakeValue::Date(NaiveDate::from_ymd_opt(2024, 2, 29).unwrap()),
+                    ],
+                    vec![
+                        SnowflakeValue::Binary(vec![0xca, 0xfe]),
+                        SnowflakeValue::Variant(json!({"a": [1]})),
+                        SnowflakeValue::TimestampNtz(
+                            NaiveDate::from_ymd_opt(2024, 1, 2)
+                                .unwrap()
+                                .and_hms_opt(3, 4, 5)
+                                .unwrap(),
+                        ),
+                    ],
+                ]),
+            )
+            .await
+            .unwrap();
+        assert_eq!(summary.rows_loaded, 3);
+
+        let uploads = server.stage_uploads().await;
+        assert_eq!(uploads.len(), 1);
+        let (file_name, upload) = &uploads[0];
+        assert_eq!(file_name, "part_00000.csv.gz");
+        assert!(upload.headers.contains_key("x-goog-meta-sfc-digest"));
+        assert_eq!(
+            gunzip(&upload.body),
+            "\"-12.50\",\"say \"\"hi\"\", twice\",\n\
+             \"TRUE\",\"\",\"2024-02-29\"\n\
+             \"CAFE\",\"{\"\"a\"\":[1]}\",\"2024-01-02 03:04:05.000000000\"\n"
+        );
+
+        let put: serde_json::Value = server.requests_to("/queries/v1/query-request").await[0]
+            .body_json()
+            .unwrap();
+        let put = put["sqlText"].as_str().unwrap();
+        let prefix = put
+            .split_once("'@peerdb_stage/")
+            .and_then(|(_, rest)| rest.split_once("/'"))
+            .unwrap()
+            .0;
+        assert!(prefix.starts_with("peerdb_load_"), "{put}");
+        assert!(put.contains("SOURCE_COMPRESSION = GZIP"), "{put}");
+        assert_eq!(
+            statements(&server).await,
+            [
+                format!(
+                    "COPY INTO \"public_users\" FROM @peerdb_stage/{prefix} FILE_FORMAT = \
+                     (TYPE = CSV COMPRESSION = GZIP FIELD_OPTIONALLY_ENCLOSED_BY = '\"' \
+                     NULL_IF = () BINARY_FORMAT = HEX) ON_ERROR = CONTINUE"
+                ),
+                format!("REMOVE @peerdb_stage/{prefix}"),
+            ]
+        );
+        // the session is logged out once the files are up
+        assert_eq!(server.requests_to("/session").await.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn starts_new_files_past_the_max_size() {
+        let server = MockSnowflakeServer::start().await;
+        server.mount_stage(None).await;
+        server.mount_statement(copy_result(&[])).await;
+        let loader = SnowflakeBulkLoader::new(Arc::new(server.client()), "peerdb_stage")
+            .with_compression(StagedFileCompression::None)
+            .with_max_file_size(10);
+
+        let ids = (0..5)
+            .map(|id| {
+                vec![SnowflakeValue::Number {
+                    value: id,
+                    scale: 0,
+                }]
+            })
+            .collect();
+        loader.load_rows("t", rows(ids)).await.unwrap();
+        let files: Vec<_> = server
+            .stage_uploads()
+            .await
+            .into_iter()
+            .map(|(file_name, upload)| (file_name, String::from_utf8(upload.body).unwrap()))
+            .collect();
+        assert_eq!(
+            files,
+            [
+                (
+                    "part_00000.csv".to_string(),
+                    "\"0\"\n\"1\"\n\"2\"\n".to_string()
+                ),
+                ("part_00001.csv".to_string(), "\"3\"\n\"4\"\n".to_string()),
+            ]
+        );
+        // a single session for all of them
+        assert_eq!(
+            server.requests_to("/session/v1/login-request").await.len(),
+            1
+        );
+    }
+
+    #[tokio::test]
+    async fn encrypts_files_for_encrypted_stages() {
+        let server = MockSnowflakeServer::start().await;
+        server
+            .mount_stage(Some(json!({
+                "queryStageMasterKey": "AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA=",
+                "queryId": "01b2-query",
+                "smkId": 7,
+            })))
+            .await;
+        server.mount_statement(copy_result(&[])).await;
+        let loader = SnowflakeBulkLoader::new(Arc::new(server.client()), "peerdb_stage");
+
+        let row = vec![SnowflakeValue::Text("secret".to_string())];
+        loader.load_rows("t", rows(vec![row])).await.unwrap();
+        let (_, upload) = &server.stage_uploads().await[0];
+        let encryption: serde_json::Value =
+            serde_json::from_slice(upload.headers["x-goog-meta-encryptiondata"].as_bytes())
+                .unwrap();
+        assert_eq!(encryption["EncryptionMode"], "FullBlob");
+        let matdesc: serde_json::Value =
+            serde_json::from_slice(upload.headers["x-goog-meta-matdesc"].as_bytes()).unwrap();
+        assert_eq!(matdesc["keySize"], "256");
+        // neither gzip nor CSV
+        assert_ne!(&upload.body[..2], [0x1f, 0x8b]);
+        assert_eq!(upload.body.len() % 16, 0);
+    }
+
+    #[cfg(feature = "arrow")]
+    #[tokio::test]
+    async fn stages_arrow_batches() {
+        use arrow::{
+            array::{Int64Array, StringArray},
+            record_batch::RecordBatch,
+        };
+
+        let server = MockSnowflakeServer::start().await;
+        server.mount_stage(None).await;
+        server.mount_statement(copy_result(&[])).await;
+        let loader = SnowflakeBulkLoader::new(Arc::new(server.client()), "peerdb_stage")
+            .with_compression(StagedFileCompression::None);
+
+        let batch = RecordBatch::try_from_iter([
+            ("id", Arc::new(Int64Array::from(vec![1, 2])) as _),
+            (
+                "name",
+                Arc::new(StringArray::from(vec![Some("a,\"b\""), None])) as _,
+            ),
+        ])
+        .unwrap();
+        let batches = futures::stream::iter([Ok(batch)]);
+        loader.load_arrow("t", batches).await.unwrap();
+        let (_, upload) = &server.stage_uploads().await[0];
+        assert_eq!(
+            String::from_utf8_lossy(&upload.body),
+            "\"1\",\"a,\"\"b\"\"\"\n\"2\",\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn skips_the_copy_without_rows() {
+        let server = MockSnowflakeServer::start().await;
+        server.mount_stage(None).await;
+        let loader = SnowflakeBulkLoader::new(Arc::new(server.client()), "peerdb_stage");
+
+        let summary = loader.load_rows("t", rows(Vec::new())).await.unwrap();
+        assert_eq!(summary, LoadSummary::default());
+        assert!(server.stage_uploads().await.is_empty());
+        assert!(statements(&server).await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn removes_uploaded_files_when_the_rows_fail() {
+        let server = MockSnowflakeServer::start().await;
+        server.mount_stage(None).await;
+        server.mount_statement(copy_result(&[])).await;
+        let loader = SnowflakeBulkLoader::new(Arc::new(server.client()), "peerdb_stage")
+            .with_max_file_size(1);
+
+        let rows = futures::stream::iter([
+            Ok(vec![SnowflakeValue::Boolean(false)]),
+            Err(SnowflakeError::Cancelled),
+        ]);
+        let err = loader.load_rows("t", rows).await.unwrap_err();
+        assert!(matches!(err, SnowflakeError::Cancelled), "{err:?}");
+        assert_eq!(server.stage_uploads().await.len(), 1);
+        let statements = statements(&server).await;
+        assert_eq!(statements.len(), 1);
+        assert!(statements[0].starts_with("REMOVE @peerdb_stage/peerdb_load_"));
+    }
+
+    #[tokio::test]
+    async fn refuses_files_it_cant_write() {
+        let server = MockSnowflakeServer::start().await;
+        let loader = SnowflakeBulkLoader::new(Arc::new(server.client()), "peerdb_stage")
+            .with_file_format(StagedFileFormat::Parquet);
+
+        let err = loader.load_rows("t", rows(Vec::new())).await.unwrap_err();
+        assert!(matches!(err, SnowflakeError::FileTransfer(_)), "{err:?}");
+    }
+
+    #[tokio::test]
+    async fn removes_staged_files_when_copy_fails() {
+        let server = MockSnowflakeServer::start().await;
+        server.mount_statement(copy_result(&[])).await;
+        server.fail_next("POST", "/api/v2/statements", 422, 1).await;
+        let loader = SnowflakeBulkLoader::new(Arc::new(server.client()), "peerdb_stage");
+
+        assert!(loader.load("users", "batch_2").await.is_err());
+        let statements = statements(&server).await;
+        assert_eq!(statements.len(), 2);
+        assert_eq!(statements[1], "REMOVE @peerdb_stage/batch_2");
+    }
+}
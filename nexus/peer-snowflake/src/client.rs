@@ -0,0 +1,2399 @@
+use std::collections::BTreeMap;
+use std::io::Write;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+
+use chrono_tz::Tz;
+use flate2::{Compression, write::GzEncoder};
+use pt::peerdb_peers::SnowflakeConfig;
+use reqwest::{RequestBuilder, Response, StatusCode, header};
+use secrecy::ExposeSecret;
+use serde::{Deserialize, Serialize};
+use tokio::{
+    sync::mpsc,
+    time::{Instant, sleep, timeout_at},
+};
+use tokio_util::sync::CancellationToken;
+use tracing::{Instrument, info, warn};
+use uuid::Uuid;
+
+use crate::{
+    auth::SnowflakeAuthError,
+    bind::{self, Binding, SnowflakeBind},
+    breaker::{CircuitBreaker, CircuitBreakerPolicy},
+    credential::{self, SnowflakeCredential},
+    decode::{BinaryFormat, SnowflakeDecodeError, SnowflakeValue, decode_cell},
+    error::{ErrorClass, SnowflakeError},
+    health::HealthTracker,
+    metrics::{SnowflakeMetrics, SnowflakeMetricsSnapshot},
+    partition::{self, SnowflakeRow, SnowflakeRowStream},
+    pool::ConcurrencyLimit,
+    retry::{DEFAULT_MAX_TOTAL_RETRIES, PollPolicy, RetryBudget, RetryPolicy},
+    syntax,
+    types::SnowflakeDataType,
+};
+
+const SNOWFLAKE_HOST_SUFFIX: &str = ".snowflakecomputing.com";
+const STATEMENTS_PATH: &str = "/api/v2/statements";
+
+// how much longer than the statement timeout `execute` waits for the result
+const CLIENT_DEADLINE_GRACE: Duration = Duration::from_secs(5);
+// Request bodies past this are gzipped. Below it, statements are a few KiB of SQL that gain
+// little, while bulk binds and batches of generated SQL past it shrink to a fraction of their
+// size as JSON, for about a millisecond of CPU per MiB.
+const GZIP_REQUEST_THRESHOLD: usize = 64 * 1024;
+const DEFAULT_CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+const DEFAULT_READ_TIMEOUT: Duration = Duration::from_secs(30);
+/// `CLIENT_APPLICATION` statements are attributed to unless overridden, see
+/// [`SnowflakeClient::with_application_id`].
+const DEFAULT_APPLICATION_ID: &str = "PeerDB";
+const USER_AGENT: &str = concat!("PeerDB/", env!("CARGO_PKG_VERSION"));
+
+// The output formats every statement runs with, whatever the account or user defaults, so
+// that values which come back formatted rather than as epoch numbers are in the formats the
+// decoder parses: `2021/06/30`, `13:45:00.123456789`, `2021-06-30T13:45:00.123456789` and
+// `2021-06-30T13:45:00.123456789+0200`. `FF` is all nine fractional digits.
+const DATE_OUTPUT_FORMAT: &str = "YYYY/MM/DD";
+const TIME_OUTPUT_FORMAT: &str = "HH24:MI:SS.FF";
+const TIMESTAMP_OUTPUT_FORMAT: &str = "YYYY-MM-DDTHH24:MI:SS.FF";
+const TIMESTAMP_TZ_OUTPUT_FORMAT: &str = "YYYY-MM-DDTHH24:MI:SS.FFTZHTZM";
+
+#[derive(Debug, Serialize)]
+struct SQLStatementParameters<'a> {
+    pub date_output_format: &'a str,
+    pub time_output_format: &'a str,
+    pub binary_output_format: &'a str,
+    // what the three below fall back to, pinned too so no account default can leak through
+    pub timestamp_output_format: &'a str,
+    pub timestamp_ltz_output_format: &'a str,
+    pub timestamp_ntz_output_format: &'a str,
+    pub timestamp_tz_output_format: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub query_tag: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub timezone: Option<&'a str>,
+    #[serde(
+        rename = "MULTI_STATEMENT_COUNT",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub multi_statement_count: Option<String>,
+    #[serde(rename = "CLIENT_APPLICATION")]
+    pub client_application: &'a str,
+    #[serde(rename = "ABORT_DETACHED_QUERY")]
+    pub abort_detached_query: String,
+}
+
+/// Encoding of the rows in responses, `jsonv2` unless asked otherwise.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub(crate) enum ResultFormat {
+    #[default]
+    Json,
+    #[cfg(feature = "arrow")]
+    Arrow,
+}
+
+#[cfg_attr(not(feature = "arrow"), allow(dead_code))]
+#[derive(Debug, Serialize)]
+struct ResultSetMetadataRequest {
+    format: &'static str,
+}
+
+#[derive(Debug, Serialize)]
+struct SQLStatement<'a> {
+    statement: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    bindings: Option<&'a BTreeMap<String, Binding>>,
+    timeout: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    database: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    schema: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    warehouse: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    role: Option<&'a str>,
+    #[serde(rename = "resultSetMetaData", skip_serializing_if = "Option::is_none")]
+    result_set_metadata: Option<ResultSetMetadataRequest>,
+    parameters: SQLStatementParameters<'a>,
+}
+
+#[allow(non_snake_case)]
+#[derive(Deserialize)]
+struct QueryStatus {
+    statementHandle: String,
+}
+
+#[allow(non_snake_case)]
+#[derive(Clone, Deserialize, Debug)]
+pub(crate) struct ResultSetRowType {
+    pub(crate) name: String,
+    pub(crate) r#type: SnowflakeDataType,
+    #[serde(default)]
+    pub(crate) scale: Option<u32>,
+    #[serde(default)]
+    pub(crate) precision: Option<u32>,
+    #[serde(default)]
+    pub(crate) nullable: Option<bool>,
+}
+
+/// A result column as described by Snowflake.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ColumnMeta {
+    pub name: String,
+    pub data_type: SnowflakeDataType,
+    pub nullable: bool,
+    /// Total digits of a `NUMBER`.
+    pub precision: Option<u32>,
+    /// Digits after the decimal point of a `NUMBER`, or of the fractional seconds of times.
+    pub scale: Option<u32>,
+}
+
+impl From<&ResultSetRowType> for ColumnMeta {
+    fn from(row_type: &ResultSetRowType) -> Self {
+        Self {
+            name: row_type.name.clone(),
+            data_type: row_type.r#type,
+            // Snowflake only leaves nullability out when it couldn't work it out
+            nullable: row_type.nullable.unwrap_or(true),
+            precision: row_type.precision,
+            scale: row_type.scale,
+        }
+    }
+}
+
+#[allow(non_snake_case, dead_code)]
+#[derive(Deserialize, Debug)]
+pub(crate) struct ResultSetPartitionInfo {
+    pub(crate) rowCount: u64,
+    pub(crate) uncompressedSize: u64,
+    compressedSize: Option<u64>,
+}
+
+#[allow(non_snake_case)]
+#[derive(Deserialize, Debug, Default)]
+pub(crate) struct StatementStats {
+    #[serde(default)]
+    pub(crate) bytesScanned: u64,
+}
+
+#[allow(non_snake_case)]
+#[derive(Deserialize, Debug)]
+pub(crate) struct ResultSetMetadata {
+    pub(crate) partitionInfo: Vec<ResultSetPartitionInfo>,
+    pub(crate) rowType: Vec<ResultSetRowType>,
+}
+
+/// What a statement cost and produced, for logging and cost dashboards.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct QueryStats {
+    /// Bytes the statement read, 0 if Snowflake didn't report it.
+    pub bytes_scanned: u64,
+    /// Rows of the result, over all partitions.
+    pub rows_produced: u64,
+    pub partition_count: usize,
+}
+
+/// Caps on the size of a result read through [`SnowflakeResultSet::into_rows`], checked
+/// against the sizes Snowflake reports for each partition. The stream fails with
+/// [`SnowflakeError::ResultTooLarge`] when it reaches the partition that crosses a cap,
+/// without fetching it or any after it.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ResultLimits {
+    /// Rows over all partitions, unlimited by default.
+    pub max_rows: Option<u64>,
+    /// Uncompressed bytes over all partitions, unlimited by default.
+    pub max_bytes: Option<u64>,
+}
+
+impl ResultLimits {
+    /// Index of the first of `partitions` that takes the result past a cap, with the error to
+    /// fail on when reaching it.
+    pub(crate) fn first_exceeded(
+        &self,
+        partitions: &[ResultSetPartitionInfo],
+    ) -> Option<(usize, SnowflakeError)> {
+        let (mut rows, mut bytes) = (0, 0);
+        partitions
+            .iter()
+            .enumerate()
+            .find_map(|(index, partition)| {
+                rows += partition.rowCount;
+                bytes += partition.uncompressedSize;
+                [
+                    ("max_rows", self.max_rows, rows),
+                    ("max_bytes", self.max_bytes, bytes),
+                ]
+                .into_iter()
+                .find_map(|(limit, max, total)| {
+                    let max = max.filter(|&max| total > max)?;
+                    Some((index, SnowflakeError::ResultTooLarge { limit, max }))
+                })
+            })
+    }
+}
+
+/// The first partition of a statement's result, as returned by the SQL API.
+#[allow(non_snake_case)]
+#[derive(Deserialize, Debug)]
+pub struct SnowflakeResultSet {
+    pub(crate) statementHandle: String,
+    #[serde(default)]
+    pub(crate) queryId: Option<String>,
+    #[serde(default)]
+    pub(crate) data: Vec<SnowflakeRow>,
+    #[cfg_attr(not(feature = "arrow"), allow(dead_code))]
+    #[serde(default)]
+    pub(crate) rowsetBase64: Option<String>,
+    pub(crate) resultSetMetaData: ResultSetMetadata,
+    #[serde(default)]
+    pub(crate) stats: Option<StatementStats>,
+    // handles of the statements of a multi-statement request, in order
+    #[serde(default)]
+    pub(crate) statementHandles: Option<Vec<String>>,
+    #[serde(skip)]
+    pub(crate) request_id: Uuid,
+    // the session TIMEZONE the statement ran with, UTC unless the client sets one
+    #[serde(skip)]
+    pub(crate) timezone: Option<Tz>,
+    #[serde(skip)]
+    pub(crate) binary_format: BinaryFormat,
+    // what is left of the statement's retries for fetching the other partitions
+    #[serde(skip)]
+    pub(crate) retry_budget: Arc<RetryBudget>,
+}
+
+impl SnowflakeResultSet {
+    pub fn statement_handle(&self) -> &str {
+        &self.statementHandle
+    }
+
+    /// The id Snowflake shows for the statement in its UI and `QUERY_HISTORY`. Snowflake only
+    /// sends one when it differs from the statement handle, so this is the handle otherwise.
+    pub fn query_id(&self) -> &str {
+        self.queryId.as_deref().unwrap_or(&self.statementHandle)
+    }
+
+    /// The `requestId` the statement was submitted with.
+    pub fn request_id(&self) -> Uuid {
+        self.request_id
+    }
+
+    pub fn columns(&self) -> Vec<ColumnMeta> {
+        self.resultSetMetaData
+            .rowType
+            .iter()
+            .map(ColumnMeta::from)
+            .collect()
+    }
+
+    pub fn column_names(&self) -> impl Iterator<Item = &str> {
+        self.resultSetMetaData
+            .rowType
+            .iter()
+            .map(|row_type| row_type.name.as_str())
+    }
+
+    /// Rows of the first partition; values are the raw strings sent by Snowflake.
+    pub fn rows(&self) -> &[SnowflakeRow] {
+        &self.data
+    }
+
+    pub fn partition_count(&self) -> usize {
+        self.resultSetMetaData.partitionInfo.len()
+    }
+
+    pub fn stats(&self) -> QueryStats {
+        QueryStats {
+            bytes_scanned: self.stats.as_ref().map_or(0, |stats| stats.bytesScanned),
+            rows_produced: self
+                .resultSetMetaData
+                .partitionInfo
+                .iter()
+                .map(|partition| partition.rowCount)
+                .sum(),
+            partition_count: self.partition_count(),
+        }
+    }
+
+    /// Decodes `row` (of this result set) into typed values, one per column.
+    pub fn decode_row(
+        &self,
+        row: &SnowflakeRow,
+    ) -> Result<Vec<SnowflakeValue>, SnowflakeDecodeError> {
+        decode_row(
+            &self.resultSetMetaData.rowType,
+            row,
+            self.timezone,
+            self.binary_format,
+        )
+    }
+
+    /// Streams the rows of every partition, fetching the ones after the first through `client`.
+    pub fn into_rows(self, client: Arc<SnowflakeClient>) -> SnowflakeRowStream {
+        SnowflakeRowStream::new(self, client)
+    }
+
+    /// Decodes the rows of every partition on a spawned task into a channel of `capacity` rows.
+    /// The task waits while the channel is full, so partitions are fetched only as fast as the
+    /// receiver takes rows, and stops after the first error or once the receiver is dropped.
+    ///
+    /// # Panics
+    ///
+    /// If `capacity` is 0.
+    pub fn into_decoded_channel(
+        self,
+        client: Arc<SnowflakeClient>,
+        capacity: usize,
+    ) -> mpsc::Receiver<Result<Vec<SnowflakeValue>, SnowflakeError>> {
+        partition::spawn_decoded_channel(self.into_rows(client), capacity)
+    }
+}
+
+pub(crate) fn decode_row(
+    row_type: &[ResultSetRowType],
+    row: &SnowflakeRow,
+    timezone: Option<Tz>,
+    binary_format: BinaryFormat,
+) -> Result<Vec<SnowflakeValue>, SnowflakeDecodeError> {
+    let timezone = timezone.unwrap_or(Tz::UTC);
+    row_type
+        .iter()
+        .zip(row)
+        .map(|(row_type, cell)| decode_cell(row_type, cell.as_deref(), timezone, binary_format))
+        .collect()
+}
+
+/// The SQL API host of an account identifier, keeping the region, cloud and `.global` or
+/// `.privatelink` segments that the JWT claims drop:
+/// `xy12345.us-east-1.aws` is reached at `xy12345.us-east-1.aws.snowflakecomputing.com`.
+///
+/// Also accepts a full account URL pasted as the identifier, and replaces the underscores
+/// Snowflake allows in account names, which aren't valid in hostnames.
+pub fn parse_account_host(raw_account: &str) -> String {
+    let account = raw_account.trim().to_lowercase();
+    let account = account
+        .strip_prefix("https://")
+        .unwrap_or(&account)
+        .trim_end_matches('/');
+    let account = account
+        .strip_suffix(SNOWFLAKE_HOST_SUFFIX)
+        .unwrap_or(account);
+    format!("{}{SNOWFLAKE_HOST_SUFFIX}", account.replace('_', "-"))
+}
+
+/// The JSON of a request body, gzipped when it is past [`GZIP_REQUEST_THRESHOLD`].
+fn encode_body(body: &impl Serialize) -> Result<(Vec<u8>, bool), SnowflakeError> {
+    let json = serde_json::to_vec(body).map_err(std::io::Error::from)?;
+    if json.len() <= GZIP_REQUEST_THRESHOLD {
+        return Ok((json, false));
+    }
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::fast());
+    encoder.write_all(&json)?;
+    Ok((encoder.finish()?, true))
+}
+
+fn describe_query(sql: &str) -> String {
+    let sql = sql.trim().trim_end_matches(';');
+    // on separate lines so that a trailing line comment doesn't swallow the closing paren
+    format!("SELECT * FROM (\n{sql}\n) WHERE 1=0")
+}
+
+// unset fields of a peer config are empty strings; those are left out of requests
+fn non_empty(value: &str) -> Option<String> {
+    (!value.is_empty()).then(|| value.to_string())
+}
+
+#[allow(non_snake_case)]
+#[derive(Deserialize)]
+pub(crate) struct PartitionResult {
+    #[serde(default)]
+    pub(crate) data: Vec<SnowflakeRow>,
+    #[cfg_attr(not(feature = "arrow"), allow(dead_code))]
+    #[serde(default)]
+    pub(crate) rowsetBase64: Option<String>,
+}
+
+/// Timeouts of the HTTP requests a [`SnowflakeClient`] sends, so a stalled gateway fails the
+/// call instead of hanging it. Requests that time out fail with
+/// [`SnowflakeError::RequestTimeout`] and are retried like other transient errors.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct HttpTimeouts {
+    /// Time to establish a connection, 10s by default.
+    pub connect: Duration,
+    /// Time to wait for each read on a connection, 30s by default. Polls return right away
+    /// while a statement is running, so this doesn't bound how long statements run.
+    pub read: Duration,
+    /// Time for a whole call such as [`SnowflakeClient::execute`], retries and polling
+    /// included, after which it fails with [`SnowflakeError::OperationTimeout`] and the
+    /// statement is cancelled. Unbounded by default, leaving it to the statement timeout.
+    pub total: Option<Duration>,
+}
+
+impl Default for HttpTimeouts {
+    fn default() -> Self {
+        Self {
+            connect: DEFAULT_CONNECT_TIMEOUT,
+            read: DEFAULT_READ_TIMEOUT,
+            total: None,
+        }
+    }
+}
+
+/// Passed to the progress callback on every poll of a statement that is still running.
+#[derive(Debug)]
+pub struct StatementProgress<'a> {
+    pub statement_handle: &'a str,
+    /// Time since polling for this submission of the statement started.
+    pub elapsed: Duration,
+    /// Polls answered with "still running" so far, starting at 1.
+    pub poll: u32,
+}
+
+/// Called on the polling task itself, so it must return quickly and must not block, e.g. by
+/// storing the progress in an atomic or sending it on a channel with `try_send`.
+pub type ProgressCallback = Arc<dyn Fn(&StatementProgress) + Send + Sync>;
+
+/// Whether a client checks its key-pair JWT against the local clock before each statement, see
+/// [`SnowflakeClient::with_jwt_expiry_check`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum JwtExpiryCheck {
+    #[default]
+    Off,
+    /// Logs a warning and sends the statement anyway.
+    Warn,
+    /// Fails the statement with [`SnowflakeAuthError::JwtExpiredLocally`].
+    Error,
+}
+
+/// Settings for a single call to [`SnowflakeClient::execute_with_options`].
+#[derive(Default)]
+pub struct ExecuteOptions {
+    timeout: Option<Duration>,
+    query_tag: Option<String>,
+    warehouse: Option<String>,
+    database: Option<String>,
+    schema: Option<String>,
+    multi_statement_count: Option<usize>,
+    cancellation: Option<CancellationToken>,
+    pub(crate) bindings: Option<BTreeMap<String, Binding>>,
+    pub(crate) result_format: ResultFormat,
+    progress: Option<ProgressCallback>,
+}
+
+fn identifier(kind: &'static str, name: String) -> Result<String, SnowflakeError> {
+    if !syntax::is_valid_identifier(&name) {
+        return Err(SnowflakeError::InvalidIdentifier { kind, name });
+    }
+    Ok(name)
+}
+
+impl std::fmt::Debug for ExecuteOptions {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ExecuteOptions")
+            .field("timeout", &self.timeout)
+            .field("query_tag", &self.query_tag)
+            .field("warehouse", &self.warehouse)
+            .field("database", &self.database)
+            .field("schema", &self.schema)
+            .field("multi_statement_count", &self.multi_statement_count)
+            .field("cancellation", &self.cancellation)
+            .field("bindings", &self.bindings)
+            .field("result_format", &self.result_format)
+            .field("progress", &self.progress.is_some())
+            .finish()
+    }
+}
+
+impl ExecuteOptions {
+    /// Lets Snowflake run the statement for at most `timeout` (rounded up to whole seconds)
+    /// instead of the peer's `query_timeout`. `execute` also gives up waiting slightly after
+    /// that, in case Snowflake doesn't cancel the statement itself.
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    /// Tags the statement with `query_tag` instead of the client's.
+    pub fn with_query_tag(mut self, query_tag: impl Into<String>) -> Self {
+        self.query_tag = Some(query_tag.into());
+        self
+    }
+
+    /// Runs the statement on `warehouse` instead of the client's, e.g. a larger one for an
+    /// occasional heavy query. Fails if `warehouse` isn't a valid identifier.
+    pub fn with_warehouse(mut self, warehouse: impl Into<String>) -> Result<Self, SnowflakeError> {
+        self.warehouse = Some(identifier("warehouse", warehouse.into())?);
+        Ok(self)
+    }
+
+    /// Resolves unqualified names in the statement against `database` instead of the client's,
+    /// for this statement only. Fails if `database` isn't a valid identifier.
+    pub fn with_database(mut self, database: impl Into<String>) -> Result<Self, SnowflakeError> {
+        self.database = Some(identifier("database", database.into())?);
+        Ok(self)
+    }
+
+    /// Resolves unqualified names in the statement against `schema` instead of the client's,
+    /// for this statement only. Fails if `schema` isn't a valid identifier.
+    pub fn with_schema(mut self, schema: impl Into<String>) -> Result<Self, SnowflakeError> {
+        self.schema = Some(identifier("schema", schema.into())?);
+        Ok(self)
+    }
+
+    /// Calls `on_progress` each time Snowflake reports the statement as still running, so that
+    /// callers can show it is alive. Statements that finish on submission never call it.
+    pub fn with_progress(mut self, on_progress: ProgressCallback) -> Self {
+        self.progress = Some(on_progress);
+        self
+    }
+}
+
+// how long a statement has waited for its warehouse to resume so far
+#[derive(Default)]
+struct WarehouseResume {
+    waits: u32,
+    waited: Duration,
+}
+
+enum QueryAttemptResult {
+    ResultSetReceived { result_set: SnowflakeResultSet },
+    KeepPolling,
+    ErrorAbort { status: StatusCode, body: String },
+}
+
+/// What's needed to reach a submitted statement without borrowing the [`SnowflakeClient`].
+#[derive(Clone)]
+pub(crate) struct StatementRef {
+    url: String,
+    credential: Arc<dyn SnowflakeCredential>,
+    reqwest_client: reqwest::Client,
+}
+
+impl StatementRef {
+    pub(crate) fn url(&self) -> &str {
+        &self.url
+    }
+
+    pub(crate) async fn cancel(&self) -> Result<(), SnowflakeError> {
+        let request = self.reqwest_client.post(format!("{}/cancel", self.url));
+        let response = credential::authorize(request, self.credential.as_ref())
+            .await?
+            .send()
+            .await?;
+        let status = response.status();
+        if status != StatusCode::OK {
+            return Err(SnowflakeError::from_response(
+                status,
+                response.text().await?,
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// Fires a best-effort cancel for `statement` when dropped, unless it was taken out first.
+struct CancelOnDrop {
+    statement: Option<StatementRef>,
+}
+
+impl Drop for CancelOnDrop {
+    fn drop(&mut self) {
+        let Some(statement) = self.statement.take() else {
+            return;
+        };
+        let Ok(runtime) = tokio::runtime::Handle::try_current() else {
+            warn!("No runtime to cancel abandoned statement {}", statement.url);
+            return;
+        };
+        runtime.spawn(async move {
+            info!("Cancelling abandoned statement {}", statement.url);
+            if let Err(err) = statement.cancel().await {
+                warn!("Failed to cancel statement {}: {}", statement.url, err);
+            }
+        });
+    }
+}
+
+/// Runs statements through the Snowflake SQL REST API (`/api/v2/statements`).
+pub struct SnowflakeClient {
+    // only used to tag traces, requests go to `endpoint_url`
+    account: String,
+    endpoint_url: String,
+    credential: Arc<dyn SnowflakeCredential>,
+    reqwest_client: reqwest::Client,
+    query_timeout: u64,
+    database: Option<String>,
+    schema: Option<String>,
+    warehouse: Option<String>,
+    role: Option<String>,
+    query_tag: Option<String>,
+    application_id: String,
+    abort_detached_query: bool,
+    timezone: Option<Tz>,
+    binary_format: BinaryFormat,
+    retry_policy: RetryPolicy,
+    max_total_retries: u32,
+    poll_policy: PollPolicy,
+    operation_timeout: Option<Duration>,
+    result_limits: ResultLimits,
+    jwt_expiry_check: JwtExpiryCheck,
+    wait_for_warehouse_resume: Duration,
+    metrics: Arc<SnowflakeMetrics>,
+    health: HealthTracker,
+    circuit_breaker: Option<CircuitBreaker>,
+    concurrency_limit: Option<Arc<ConcurrencyLimit>>,
+}
+
+pub(crate) fn default_http_client() -> reqwest::ClientBuilder {
+    let mut default_headers = header::HeaderMap::new();
+    default_headers.insert(
+        reqwest::header::USER_AGENT,
+        header::HeaderValue::from_static(USER_AGENT),
+    ); // SnowFlake needs a user agent.
+
+    reqwest::ClientBuilder::new()
+        .gzip(true)
+        .default_headers(default_headers)
+        .connect_timeout(DEFAULT_CONNECT_TIMEOUT)
+        .read_timeout(DEFAULT_READ_TIMEOUT)
+}
+
+impl SnowflakeClient {
+    pub fn new(
+        config: &SnowflakeConfig,
+        credential: Arc<dyn SnowflakeCredential>,
+    ) -> Result<Self, SnowflakeError> {
+        let reqwest_client = default_http_client().build()?;
+        Ok(SnowflakeClient::with_http_client(
+            config,
+            credential,
+            reqwest_client,
+        ))
+    }
+
+    /// The HTTP client settings [`SnowflakeClient::new`] uses: the `User-Agent` Snowflake asks
+    /// for, gzip and the default [`HttpTimeouts`]. Build on this for
+    /// [`SnowflakeClient::with_http_client`], e.g. with a proxy or a custom root certificate.
+    pub fn http_client_builder() -> reqwest::ClientBuilder {
+        default_http_client()
+    }
+
+    /// Same as [`SnowflakeClient::new`], sending requests through `reqwest_client` instead of
+    /// one of its own, e.g. to go through a corporate proxy or trust a private CA, or to share
+    /// its connections with other clients. Start it from
+    /// [`SnowflakeClient::http_client_builder`] so that it keeps the settings Snowflake expects.
+    pub fn with_http_client(
+        config: &SnowflakeConfig,
+        credential: Arc<dyn SnowflakeCredential>,
+        reqwest_client: reqwest::Client,
+    ) -> Self {
+        Self {
+            account: config.account_id.clone(),
+            endpoint_url: format!(
+                "https://{}{STATEMENTS_PATH}",
+                parse_account_host(&config.account_id)
+            ),
+            credential,
+            reqwest_client,
+            query_timeout: config.query_timeout,
+            database: non_empty(&config.database),
+            schema: None,
+            warehouse: non_empty(&config.warehouse),
+            role: non_empty(&config.role),
+            query_tag: None,
+            application_id: DEFAULT_APPLICATION_ID.to_string(),
+            abort_detached_query: true,
+            timezone: None,
+            binary_format: BinaryFormat::Hex,
+            retry_policy: RetryPolicy::default(),
+            max_total_retries: DEFAULT_MAX_TOTAL_RETRIES,
+            poll_policy: PollPolicy::default(),
+            operation_timeout: None,
+            result_limits: ResultLimits::default(),
+            jwt_expiry_check: JwtExpiryCheck::Off,
+            wait_for_warehouse_resume: Duration::ZERO,
+            metrics: Arc::default(),
+            health: HealthTracker::default(),
+            circuit_breaker: None,
+            concurrency_limit: None,
+        }
+    }
+
+    #[cfg(test)]
+    pub(crate) fn with_endpoint_url(mut self, endpoint_url: String) -> Self {
+        self.endpoint_url = endpoint_url;
+        self
+    }
+
+    /// Replaces the default [`HttpTimeouts`]. This builds a new HTTP client, so a client from
+    /// a [`SnowflakeClientPool`](crate::SnowflakeClientPool) no longer shares its connections,
+    /// and one passed to [`SnowflakeClient::with_http_client`] is dropped: set the timeouts
+    /// on that one instead.
+    pub fn with_http_timeouts(mut self, timeouts: HttpTimeouts) -> Result<Self, SnowflakeError> {
+        self.reqwest_client = default_http_client()
+            .connect_timeout(timeouts.connect)
+            .read_timeout(timeouts.read)
+            .build()?;
+        self.operation_timeout = timeouts.total;
+        Ok(self)
+    }
+
+    pub(crate) fn with_concurrency_limit(mut self, limit: Arc<ConcurrencyLimit>) -> Self {
+        self.concurrency_limit = Some(limit);
+        self
+    }
+
+    /// Sends requests to `base_url`, e.g. `https://<account>.privatelink.snowflakecomputing.com`
+    /// for an account reached through PrivateLink, instead of the public host derived from the
+    /// account identifier. The credential is unaffected: its JWT claims still name the account.
+    pub fn with_base_url(mut self, base_url: &str) -> Result<Self, SnowflakeError> {
+        let invalid = |reason: &str| SnowflakeError::InvalidBaseUrl {
+            url: base_url.to_string(),
+            reason: reason.to_string(),
+        };
+        let url = reqwest::Url::parse(base_url).map_err(|err| invalid(&err.to_string()))?;
+        if url.scheme() != "https" {
+            return Err(invalid("only https is supported"));
+        }
+        if url.host_str().is_none_or(str::is_empty) {
+            return Err(invalid("no host"));
+        }
+        if url.path() != "/" || url.query().is_some() || url.fragment().is_some() {
+            return Err(invalid("expected a bare scheme and host"));
+        }
+        self.endpoint_url = format!("{}{STATEMENTS_PATH}", base_url.trim_end_matches('/'));
+        Ok(self)
+    }
+
+    /// Default schema for unqualified names in statements run by this client.
+    pub fn with_schema(mut self, schema: impl Into<String>) -> Self {
+        self.schema = Some(schema.into());
+        self
+    }
+
+    /// Role statements run as, instead of the peer's role (or the user's default role).
+    pub fn with_role(mut self, role: impl Into<String>) -> Self {
+        self.role = Some(role.into());
+        self
+    }
+
+    /// Warehouse statements run on, instead of the peer's warehouse (or the user's default).
+    pub fn with_warehouse(mut self, warehouse: impl Into<String>) -> Self {
+        self.warehouse = Some(warehouse.into());
+        self
+    }
+
+    /// `QUERY_TAG` set on every statement, so they can be attributed in `QUERY_HISTORY`.
+    pub fn with_query_tag(mut self, query_tag: impl Into<String>) -> Self {
+        self.query_tag = Some(query_tag.into());
+        self
+    }
+
+    /// `CLIENT_APPLICATION` sent with every statement, `PeerDB` by default, e.g. to tell the
+    /// deployments sharing a Snowflake account apart. The `User-Agent` stays `PeerDB/<version>`.
+    pub fn with_application_id(mut self, application_id: impl Into<String>) -> Self {
+        self.application_id = application_id.into();
+        self
+    }
+
+    /// Sets Snowflake's `ABORT_DETACHED_QUERY` for the client's statements, true by default:
+    /// Snowflake then aborts a statement once it has stopped hearing from the client for about
+    /// five minutes, e.g. because the process died before it could cancel it.
+    ///
+    /// Statements are submitted asynchronously and only their handle ties them to the client,
+    /// so they stay attached while `execute` polls them, and a poll dropped early cancels its
+    /// statement outright. This covers what that cancel can't reach. A handle kept to check on
+    /// later without polling it in between detaches its statement, so disable this for those.
+    pub fn with_abort_detached_query(mut self, abort_detached_query: bool) -> Self {
+        self.abort_detached_query = abort_detached_query;
+        self
+    }
+
+    /// Session `TIMEZONE` for statements run by this client, which `TIMESTAMP_LTZ` values are
+    /// rendered in. Without one the account's timezone applies, and LTZ values formatted
+    /// without an offset are decoded as UTC.
+    pub fn with_timezone(mut self, timezone: Tz) -> Self {
+        self.timezone = Some(timezone);
+        self
+    }
+
+    /// `BINARY_OUTPUT_FORMAT` for statements run by this client, [`BinaryFormat::Hex`] by
+    /// default whatever the account sets. Base64 makes large binary results a third smaller.
+    pub fn with_binary_output_format(mut self, binary_format: BinaryFormat) -> Self {
+        self.binary_format = binary_format;
+        self
+    }
+
+    /// Counts statements and their outcomes into `metrics`, e.g. to share one set of counters
+    /// between the clients of a peer.
+    pub fn with_metrics(mut self, metrics: Arc<SnowflakeMetrics>) -> Self {
+        self.metrics = metrics;
+        self
+    }
+
+    /// Current values of this client's statement counters.
+    pub fn metrics(&self) -> SnowflakeMetricsSnapshot {
+        self.metrics.snapshot()
+    }
+
+    /// How often to poll statements that are still running, see [`PollPolicy`].
+    pub fn with_poll_policy(mut self, poll_policy: PollPolicy) -> Self {
+        self.poll_policy = poll_policy;
+        self
+    }
+
+    /// Caps on the results streamed from this client, see [`ResultLimits`].
+    pub fn with_result_limits(mut self, result_limits: ResultLimits) -> Self {
+        self.result_limits = result_limits;
+        self
+    }
+
+    /// Resubmits a statement rejected because its warehouse is suspended or resuming, see
+    /// [`SnowflakeError::is_warehouse_resuming`], for up to `wait` in total, without counting
+    /// that against the retry policy. The statement's timeout is extended by as long as it
+    /// waited. Off (zero) by default, so such statements fail right away.
+    pub fn with_wait_for_warehouse_resume(mut self, wait: Duration) -> Self {
+        self.wait_for_warehouse_resume = wait;
+        self
+    }
+
+    /// Decodes the key-pair JWT before each statement and checks its expiry against the local
+    /// clock, so that a drifted clock shows up as such rather than as Snowflake rejecting the
+    /// token. Off by default, and a no-op for other credentials.
+    pub fn with_jwt_expiry_check(mut self, check: JwtExpiryCheck) -> Self {
+        self.jwt_expiry_check = check;
+        self
+    }
+
+    /// Fails statements fast with [`SnowflakeError::CircuitOpen`] once the peer has failed
+    /// repeatedly, until a probe after the policy's cool-down succeeds.
+    pub fn with_circuit_breaker(mut self, policy: CircuitBreakerPolicy) -> Self {
+        self.circuit_breaker = Some(CircuitBreaker::new(policy));
+        self
+    }
+
+    /// Retry behavior for submitting statements and fetching partitions.
+    pub fn with_retry_policy(mut self, retry_policy: RetryPolicy) -> Self {
+        self.retry_policy = retry_policy;
+        self
+    }
+
+    /// Caps the retries of a whole statement at `max_total_retries`, 20 by default, however many
+    /// requests it takes: retries of its submission, of its polls and of its partition fetches
+    /// all count towards it. Each request still retries no more than the
+    /// [`RetryPolicy`] allows.
+    pub fn with_max_total_retries(mut self, max_total_retries: u32) -> Self {
+        self.max_total_retries = max_total_retries;
+        self
+    }
+
+    /// Submits `sql` and waits for it to complete, returning the first partition of the result.
+    #[tracing::instrument(name = "peer_sflake::execute", skip_all)]
+    pub async fn execute(&self, sql: &str) -> Result<SnowflakeResultSet, SnowflakeError> {
+        self.execute_statement(sql, &ExecuteOptions::default())
+            .await
+    }
+
+    /// Like [`SnowflakeClient::execute`], with `binds` substituted for the `?` placeholders in
+    /// `sql`, in order.
+    #[tracing::instrument(name = "peer_sflake::execute_with_binds", skip_all)]
+    pub async fn execute_with_binds(
+        &self,
+        sql: &str,
+        binds: &[SnowflakeBind],
+    ) -> Result<SnowflakeResultSet, SnowflakeError> {
+        let placeholders = bind::count_placeholders(sql);
+        if placeholders != binds.len() {
+            return Err(SnowflakeError::BindCountMismatch {
+                placeholders,
+                binds: binds.len(),
+            });
+        }
+        let options = ExecuteOptions {
+            bindings: Some(bind::bindings(binds)),
+            ..Default::default()
+        };
+        self.execute_statement(sql, &options).await
+    }
+
+    /// Like [`SnowflakeClient::execute`], with per-statement `options`.
+    #[tracing::instrument(name = "peer_sflake::execute_with_options", skip_all)]
+    pub async fn execute_with_options(
+        &self,
+        sql: &str,
+        options: &ExecuteOptions,
+    ) -> Result<SnowflakeResultSet, SnowflakeError> {
+        self.execute_statement(sql, options).await
+    }
+
+    /// Like [`SnowflakeClient::execute`], failing with [`SnowflakeError::Cancelled`] as soon as
+    /// `cancellation` fires. Snowflake is then asked to cancel the statement in the background.
+    #[tracing::instrument(name = "peer_sflake::execute_cancellable", skip_all)]
+    pub async fn execute_cancellable(
+        &self,
+        sql: &str,
+        cancellation: CancellationToken,
+    ) -> Result<SnowflakeResultSet, SnowflakeError> {
+        let options = ExecuteOptions {
+            cancellation: Some(cancellation),
+            ..Default::default()
+        };
+        self.execute_statement(sql, &options).await
+    }
+
+    /// Server-side timeout in seconds, 0 meaning Snowflake's maximum.
+    fn timeout_secs(&self, options: &ExecuteOptions) -> u64 {
+        match options.timeout {
+            Some(timeout) => timeout.as_secs() + u64::from(timeout.subsec_nanos() > 0),
+            None => self.query_timeout,
+        }
+    }
+
+    pub(crate) async fn execute_statement(
+        &self,
+        sql: &str,
+        options: &ExecuteOptions,
+    ) -> Result<SnowflakeResultSet, SnowflakeError> {
+        if let Some(breaker) = &self.circuit_breaker {
+            breaker.admit()?;
+        }
+        // sent with every submission of this statement so Snowflake runs it at most once, and
+        // the id support asks for when a statement misbehaves
+        let request_id = Uuid::new_v4();
+        // the statement text, binds and credentials are deliberately kept out of the span
+        let span = tracing::info_span!(
+            "peer_sflake::statement",
+            account = %self.account,
+            %request_id,
+            statement_handle = tracing::field::Empty,
+            query_id = tracing::field::Empty,
+            attempt = tracing::field::Empty,
+            error_code = tracing::field::Empty,
+            bytes_scanned = tracing::field::Empty,
+            rows_produced = tracing::field::Empty,
+        );
+        self.metrics.record_submitted();
+        let retry_budget = Arc::new(RetryBudget::new(self.max_total_retries));
+        let run = self
+            .run_statement(sql, options, request_id, &retry_budget)
+            .instrument(span.clone());
+        // dropping `run` on timeout or cancellation cancels the statement
+        let run = async {
+            match &options.cancellation {
+                Some(cancellation) => tokio::select! {
+                    res = run => res,
+                    _ = cancellation.cancelled() => Err(SnowflakeError::Cancelled),
+                },
+                None => run.await,
+            }
+        };
+        let res = match self.operation_timeout {
+            Some(timeout) => tokio::time::timeout(timeout, run)
+                .await
+                .unwrap_or(Err(SnowflakeError::OperationTimeout { timeout })),
+            None => run.await,
+        };
+        match &res {
+            Ok(result_set) => {
+                span.record("query_id", result_set.query_id());
+                info!(
+                    "Statement with handle {} completed as query {}",
+                    result_set.statement_handle(),
+                    result_set.query_id()
+                );
+                let stats = result_set.stats();
+                span.record("bytes_scanned", stats.bytes_scanned);
+                span.record("rows_produced", stats.rows_produced);
+                self.metrics.record_success(stats.bytes_scanned);
+                self.health.record_success();
+                self.credential.record_success();
+                if let Some(breaker) = &self.circuit_breaker {
+                    breaker.record(Ok(()));
+                }
+            }
+            Err(err) => {
+                if let Some(api_error) = err.api_error() {
+                    span.record("error_code", api_error.code.as_str());
+                    if let Some(query_id) = &api_error.query_id {
+                        span.record("query_id", query_id.as_str());
+                    }
+                }
+                self.metrics.record_failure(err);
+                self.health.record_failure(err);
+                if err.class() == ErrorClass::AuthFailure {
+                    self.credential.record_auth_failure();
+                }
+                if let Some(breaker) = &self.circuit_breaker {
+                    breaker.record(Err(err));
+                }
+            }
+        }
+        res.map(|mut result_set| {
+            result_set.request_id = request_id;
+            result_set.timezone = self.timezone;
+            result_set.binary_format = self.binary_format;
+            result_set.retry_budget = retry_budget;
+            result_set
+        })
+        .map_err(|source| SnowflakeError::Statement {
+            request_id,
+            source: Box::new(source),
+        })
+    }
+
+    async fn run_statement(
+        &self,
+        sql: &str,
+        options: &ExecuteOptions,
+        request_id: Uuid,
+        retry_budget: &RetryBudget,
+    ) -> Result<SnowflakeResultSet, SnowflakeError> {
+        self.check_jwt_expiry().await?;
+        let _permit = match &self.concurrency_limit {
+            Some(limit) => Some(limit.acquire().await?),
+            None => None,
+        };
+        let timeout_secs = self.timeout_secs(options);
+        // leave Snowflake time to time the statement out itself, which also cancels it
+        let mut deadline = (timeout_secs > 0)
+            .then(|| Instant::now() + Duration::from_secs(timeout_secs) + CLIENT_DEADLINE_GRACE);
+        let span = tracing::Span::current();
+        let mut attempt: u64 = 0;
+        let mut retry = false;
+        let mut resume = WarehouseResume::default();
+        loop {
+            attempt += 1;
+            span.record("attempt", attempt);
+            if retry {
+                self.metrics.record_retry();
+            }
+            let submit = self.submit(sql, options, request_id, retry, retry_budget);
+            let query_status = match submit.await {
+                Ok(query_status) => query_status,
+                Err(err) => {
+                    self.wait_for_warehouse(err, &mut resume, &mut deadline)
+                        .await?;
+                    retry = true;
+                    continue;
+                }
+            };
+            span.record("statement_handle", query_status.statementHandle.as_str());
+            retry = true;
+            // lets the pool cancel the statement if it shuts down while it is running
+            let _tracked = self
+                .concurrency_limit
+                .as_ref()
+                .map(|limit| limit.track(self.statement_ref(&query_status.statementHandle)));
+            // cancels the statement if this future is dropped before it completes
+            let mut cancel_on_drop = CancelOnDrop {
+                statement: Some(self.statement_ref(&query_status.statementHandle)),
+            };
+
+            let statement_handle = query_status.statementHandle.clone();
+            let poll = self.query_poll(
+                query_status,
+                timeout_secs,
+                options.progress.as_ref(),
+                retry_budget,
+            );
+            let res = match deadline {
+                Some(deadline) => timeout_at(deadline, poll)
+                    .await
+                    .unwrap_or_else(|_| Err(SnowflakeError::Timeout { statement_handle })),
+                None => poll.await,
+            };
+            if !matches!(res, Err(SnowflakeError::Timeout { .. })) {
+                cancel_on_drop.statement = None;
+            }
+            match res {
+                Ok(result_set) => return Ok(result_set),
+                Err(err) => {
+                    self.wait_for_warehouse(err, &mut resume, &mut deadline)
+                        .await?
+                }
+            }
+        }
+    }
+
+    // sleeps before resubmitting a statement whose warehouse is resuming, as long as the wait
+    // for it isn't used up, and fails with `err` otherwise
+    async fn wait_for_warehouse(
+        &self,
+        err: SnowflakeError,
+        resume: &mut WarehouseResume,
+        deadline: &mut Option<Instant>,
+    ) -> Result<(), SnowflakeError> {
+        let remaining = self.wait_for_warehouse_resume.saturating_sub(resume.waited);
+        if !err.is_warehouse_resuming() || remaining.is_zero() {
+            return Err(err);
+        }
+        resume.waits += 1;
+        let wait = self.poll_policy.interval(resume.waits).min(remaining);
+        info!(
+            "Warehouse is resuming, resubmitting statement in {:?}: {}",
+            wait, err
+        );
+        sleep(wait).await;
+        resume.waited += wait;
+        if let Some(deadline) = deadline {
+            *deadline += wait;
+        }
+        Ok(())
+    }
+
+    /// Runs `statements` in one request, returning a result set for each of them in order, and
+    /// failing if there are none or Snowflake returns a different number. Snowflake stops at the
+    /// first statement that fails, and the whole call fails with its error. Statements before it
+    /// were still run, so wrap the batch in `BEGIN` and `COMMIT` if it must apply all or
+    /// nothing.
+    ///
+    /// Accounts only accept this once `MULTI_STATEMENT_COUNT` may be set through the API, which
+    /// it may by default unless an administrator set it to 1.
+    #[tracing::instrument(name = "peer_sflake::execute_batch", skip_all)]
+    pub async fn execute_batch(
+        &self,
+        statements: &[&str],
+    ) -> Result<Vec<SnowflakeResultSet>, SnowflakeError> {
+        if statements.is_empty() {
+            return Err(SnowflakeError::EmptyBatch);
+        }
+        let sql = statements
+            .iter()
+            .map(|statement| statement.trim().trim_end_matches(';'))
+            .collect::<Vec<_>>()
+            .join(";\n");
+        let options = ExecuteOptions {
+            multi_statement_count: Some(statements.len()),
+            ..Default::default()
+        };
+        let batch = self.execute_statement(&sql, &options).await?;
+        let statement_handles = batch.statementHandles.as_deref().unwrap_or_default();
+        if statement_handles.len() != statements.len() {
+            return Err(SnowflakeError::BatchMismatch {
+                statements: statements.len(),
+                results: statement_handles.len(),
+            });
+        }
+        let mut result_sets = Vec::with_capacity(statements.len());
+        for statement_handle in statement_handles {
+            let mut result_set = self
+                .fetch_statement(statement_handle, &batch.retry_budget)
+                .await?;
+            result_set.request_id = batch.request_id;
+            result_set.timezone = batch.timezone;
+            result_set.binary_format = batch.binary_format;
+            result_set.retry_budget = batch.retry_budget.clone();
+            result_sets.push(result_set);
+        }
+        Ok(result_sets)
+    }
+
+    /// The result of a statement that already completed.
+    async fn fetch_statement(
+        &self,
+        statement_handle: &str,
+        retry_budget: &RetryBudget,
+    ) -> Result<SnowflakeResultSet, SnowflakeError> {
+        let url = format!("{}/{}", self.endpoint_url, statement_handle);
+        let response = self
+            .send(retry_budget, || self.reqwest_client.get(&url))
+            .await?;
+        let status = response.status();
+        let body = response.text().await?;
+        if status != StatusCode::OK {
+            return Err(SnowflakeError::from_response(status, body));
+        }
+        serde_json::from_str(&body)
+            .map_err(|source| SnowflakeError::InvalidResponse { body, source })
+    }
+
+    /// Columns `sql` would return, found by running it with a filter that matches no rows.
+    #[tracing::instrument(name = "peer_sflake::describe", skip_all)]
+    pub async fn describe(&self, sql: &str) -> Result<Vec<ColumnMeta>, SnowflakeError> {
+        let result_set = self.execute(&describe_query(sql)).await?;
+        Ok(result_set.columns())
+    }
+
+    /// Asks Snowflake to abort the statement with handle `statement_handle`.
+    pub async fn cancel(&self, statement_handle: &str) -> Result<(), SnowflakeError> {
+        self.statement_ref(statement_handle).cancel().await
+    }
+
+    pub(crate) fn credential(&self) -> &dyn SnowflakeCredential {
+        self.credential.as_ref()
+    }
+
+    pub(crate) fn http_client(&self) -> &reqwest::Client {
+        &self.reqwest_client
+    }
+
+    pub(crate) fn endpoint_url(&self) -> &str {
+        &self.endpoint_url
+    }
+
+    /// The account's URL without the SQL API path, where the drivers' endpoints are.
+    pub(crate) fn base_url(&self) -> &str {
+        self.endpoint_url
+            .strip_suffix(STATEMENTS_PATH)
+            .unwrap_or(&self.endpoint_url)
+    }
+
+    pub(crate) fn account(&self) -> &str {
+        &self.account
+    }
+
+    /// The database, schema, warehouse and role statements run with, as the query parameters
+    /// of a login request.
+    pub(crate) fn session_parameters(&self) -> Vec<(&'static str, &str)> {
+        [
+            ("databaseName", &self.database),
+            ("schemaName", &self.schema),
+            ("warehouse", &self.warehouse),
+            ("roleName", &self.role),
+        ]
+        .into_iter()
+        .filter_map(|(name, value)| Some((name, value.as_deref()?)))
+        .collect()
+    }
+
+    /// A budget for the retries of one operation, see
+    /// [`SnowflakeClient::with_max_total_retries`].
+    pub(crate) fn retry_budget(&self) -> RetryBudget {
+        RetryBudget::new(self.max_total_retries)
+    }
+
+    pub(crate) fn health_tracker(&self) -> &HealthTracker {
+        &self.health
+    }
+
+    pub(crate) fn circuit_breaker(&self) -> Option<&CircuitBreaker> {
+        self.circuit_breaker.as_ref()
+    }
+
+    pub(crate) fn result_limits(&self) -> &ResultLimits {
+        &self.result_limits
+    }
+
+    fn statement_ref(&self, statement_handle: &str) -> StatementRef {
+        StatementRef {
+            url: format!("{}/{}", self.endpoint_url, statement_handle),
+            credential: self.credential.clone(),
+            reqwest_client: self.reqwest_client.clone(),
+        }
+    }
+
+    fn statement_request<'a>(
+        &'a self,
+        sql: &'a str,
+        options: &'a ExecuteOptions,
+    ) -> SQLStatement<'a> {
+        SQLStatement {
+            statement: sql,
+            bindings: options.bindings.as_ref(),
+            timeout: self.timeout_secs(options),
+            database: options.database.as_deref().or(self.database.as_deref()),
+            schema: options.schema.as_deref().or(self.schema.as_deref()),
+            warehouse: options.warehouse.as_deref().or(self.warehouse.as_deref()),
+            role: self.role.as_deref(),
+            result_set_metadata: match options.result_format {
+                ResultFormat::Json => None,
+                #[cfg(feature = "arrow")]
+                ResultFormat::Arrow => Some(ResultSetMetadataRequest { format: "arrowv1" }),
+            },
+            parameters: SQLStatementParameters {
+                date_output_format: DATE_OUTPUT_FORMAT,
+                time_output_format: TIME_OUTPUT_FORMAT,
+                binary_output_format: self.binary_format.parameter(),
+                timestamp_output_format: TIMESTAMP_TZ_OUTPUT_FORMAT,
+                timestamp_ltz_output_format: TIMESTAMP_TZ_OUTPUT_FORMAT,
+                timestamp_ntz_output_format: TIMESTAMP_OUTPUT_FORMAT,
+                timestamp_tz_output_format: TIMESTAMP_TZ_OUTPUT_FORMAT,
+                query_tag: options.query_tag.as_deref().or(self.query_tag.as_deref()),
+                timezone: self.timezone.map(|timezone| timezone.name()),
+                multi_statement_count: options.multi_statement_count.map(|n| n.to_string()),
+                client_application: &self.application_id,
+                abort_detached_query: self.abort_detached_query.to_string(),
+            },
+        }
+    }
+
+    async fn submit(
+        &self,
+        sql: &str,
+        options: &ExecuteOptions,
+        request_id: Uuid,
+        retry: bool,
+        retry_budget: &RetryBudget,
+    ) -> Result<QueryStatus, SnowflakeError> {
+        let (body, gzipped) = encode_body(&self.statement_request(sql, options))?;
+        // every attempt carries the same `requestId`, so that Snowflake runs the statement once
+        // even if an attempt that timed out actually reached it
+        let request_id = request_id.to_string();
+        let resent = AtomicBool::new(retry);
+        let response = self
+            .send(retry_budget, || {
+                let retry = resent.swap(true, Ordering::Relaxed).to_string();
+                let request = self
+                    .reqwest_client
+                    .post(&self.endpoint_url)
+                    .query(&[
+                        ("async", "true"),
+                        ("requestId", &request_id),
+                        ("retry", &retry),
+                    ])
+                    .header(header::CONTENT_TYPE, "application/json")
+                    .body(body.clone());
+                if gzipped {
+                    request.header(header::CONTENT_ENCODING, "gzip")
+                } else {
+                    request
+                }
+            })
+            .await?;
+        let status = response.status();
+        let body = response.text().await?;
+        if !status.is_success() {
+            return Err(SnowflakeError::from_response(status, body));
+        }
+
+        serde_json::from_str(&body)
+            .map_err(|source| SnowflakeError::InvalidResponse { body, source })
+    }
+
+    async fn query_attempt(
+        &self,
+        query_status: &QueryStatus,
+        retry_budget: &RetryBudget,
+    ) -> Result<QueryAttemptResult, SnowflakeError> {
+        let url = format!("{}/{}", self.endpoint_url, query_status.statementHandle);
+        let response = self
+            .send(retry_budget, || self.reqwest_client.get(&url))
+            .await?;
+        let status = response.status();
+        if status == StatusCode::OK {
+            Ok(QueryAttemptResult::ResultSetReceived {
+                result_set: response.json::<SnowflakeResultSet>().await?,
+            })
+        } else if status == StatusCode::ACCEPTED {
+            Ok(QueryAttemptResult::KeepPolling)
+        } else {
+            Ok(QueryAttemptResult::ErrorAbort {
+                status,
+                body: response.text().await?,
+            })
+        }
+    }
+
+    async fn check_jwt_expiry(&self) -> Result<(), SnowflakeError> {
+        if self.jwt_expiry_check == JwtExpiryCheck::Off
+            || self.credential.token_type() != credential::KEYPAIR_JWT_TOKEN_TYPE
+        {
+            return Ok(());
+        }
+        let authorization = self.credential.authorization_header().await?;
+        let jwt = authorization.expose_secret().trim_start_matches("Bearer ");
+        let Err(err) = credential::check_jwt_expiry(jwt) else {
+            return Ok(());
+        };
+        if self.jwt_expiry_check == JwtExpiryCheck::Error {
+            return Err(SnowflakeAuthError::JwtExpiredLocally(err).into());
+        }
+        warn!(
+            "JWT for Snowflake account {} fails local validation, the clock may be off: {}",
+            self.account, err
+        );
+        Ok(())
+    }
+
+    #[tracing::instrument(name = "peer_sflake::query_poll", skip_all)]
+    async fn query_poll(
+        &self,
+        query_status: QueryStatus,
+        timeout_secs: u64,
+        on_progress: Option<&ProgressCallback>,
+        retry_budget: &RetryBudget,
+    ) -> Result<SnowflakeResultSet, SnowflakeError> {
+        info!(
+            "Polling for query with handle: {}",
+            query_status.statementHandle
+        );
+        let max_wait = self
+            .poll_policy
+            .max_wait
+            .or_else(|| (timeout_secs > 0).then(|| Duration::from_secs(timeout_secs)));
+        let started = Instant::now();
+        let mut poll_count: u32 = 0;
+
+        loop {
+            info!(
+                "Poll attempt #{} for query with handle: {}",
+                poll_count, query_status.statementHandle
+            );
+            poll_count += 1;
+            let query_attempt_result = self.query_attempt(&query_status, retry_budget).await?;
+
+            match query_attempt_result {
+                QueryAttemptResult::ResultSetReceived { result_set } => {
+                    return Ok(result_set);
+                }
+                QueryAttemptResult::KeepPolling => {
+                    if let Some(on_progress) = on_progress {
+                        on_progress(&StatementProgress {
+                            statement_handle: &query_status.statementHandle,
+                            elapsed: started.elapsed(),
+                            poll: poll_count,
+                        });
+                    }
+                    let interval = self.poll_policy.interval(poll_count);
+                    if max_wait.is_some_and(|max_wait| started.elapsed() + interval > max_wait) {
+                        break;
+                    }
+                    sleep(interval).await;
+                }
+                QueryAttemptResult::ErrorAbort { status, body } => {
+                    return Err(SnowflakeError::from_response(status, body));
+                }
+            }
+        }
+        Err(SnowflakeError::Timeout {
+            statement_handle: query_status.statementHandle,
+        })
+    }
+
+    /// Sends the request made by `build`, retrying according to the client's [`RetryPolicy`]
+    /// as long as `retry_budget` lasts. Responses that aren't retryable are returned as is,
+    /// whatever their status.
+    async fn send(
+        &self,
+        retry_budget: &RetryBudget,
+        build: impl Fn() -> RequestBuilder,
+    ) -> Result<Response, SnowflakeError> {
+        self.send_with(retry_budget, Some(self.credential.as_ref()), build)
+            .await
+    }
+
+    /// Like [`SnowflakeClient::send`], for requests that `build` authorizes itself, e.g. with a
+    /// session token rather than the client's credential.
+    pub(crate) async fn send_unauthorized(
+        &self,
+        retry_budget: &RetryBudget,
+        build: impl Fn() -> RequestBuilder,
+    ) -> Result<Response, SnowflakeError> {
+        self.send_with(retry_budget, None, build).await
+    }
+
+    async fn send_with(
+        &self,
+        retry_budget: &RetryBudget,
+        credential: Option<&dyn SnowflakeCredential>,
+        build: impl Fn() -> RequestBuilder,
+    ) -> Result<Response, SnowflakeError> {
+        let mut attempt = 1;
+        loop {
+            let request = match credential {
+                Some(credential) => credential::authorize(build(), credential).await?,
+                None => build(),
+            };
+            let (err, retry_after) = match request.send().await {
+                Ok(response) if !RetryPolicy::is_retryable_status(response.status()) => {
+                    return Ok(response);
+                }
+                Ok(response) => {
+                    let retry_after = RetryPolicy::retry_after(&response);
+                    let status = response.status();
+                    let body = response.text().await?;
+                    (SnowflakeError::from_response(status, body), retry_after)
+                }
+                Err(err) => (SnowflakeError::from(err), None),
+            };
+            if attempt >= self.retry_policy.max_attempts || !err.is_transient() {
+                return Err(err);
+            }
+            if !retry_budget.take() {
+                warn!(
+                    "Snowflake request failed on attempt {}, out of retries for the statement: {}",
+                    attempt, err
+                );
+                return Err(err);
+            }
+            let delay = self.retry_policy.delay(attempt, retry_after);
+            warn!(
+                "Snowflake request failed on attempt {}/{}, retrying in {:?}: {}",
+                attempt, self.retry_policy.max_attempts, delay, err
+            );
+            sleep(delay).await;
+            self.metrics.record_retry();
+            attempt += 1;
+        }
+    }
+
+    /// Fetches the rows of partition `partition` of a completed statement.
+    pub(crate) async fn fetch_partition(
+        &self,
+        statement_handle: &str,
+        partition: usize,
+        retry_budget: &RetryBudget,
+    ) -> Result<Vec<SnowflakeRow>, SnowflakeError> {
+        let partition = self
+            .fetch_partition_result(statement_handle, partition, retry_budget)
+            .await?;
+        Ok(partition.data)
+    }
+
+    pub(crate) async fn fetch_partition_result(
+        &self,
+        statement_handle: &str,
+        partition: usize,
+        retry_budget: &RetryBudget,
+    ) -> Result<PartitionResult, SnowflakeError> {
+        let url = format!("{}/{}", self.endpoint_url, statement_handle);
+        let partition = partition.to_string();
+        let response = self
+            .send(retry_budget, || {
+                self.reqwest_client
+                    .get(&url)
+                    .query(&[("partition", &partition)])
+            })
+            .await?;
+        let status = response.status();
+        let body = response.text().await?;
+        if status != StatusCode::OK {
+            return Err(SnowflakeError::from_response(status, body));
+        }
+        serde_json::from_str::<PartitionResult>(&body)
+            .map_err(|source| SnowflakeError::InvalidResponse { body, source })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::mock::{MockSnowflakeServer, MockStatement, test_auth};
+
+    async fn running_statement_server() -> MockSnowflakeServer {
+        let server = MockSnowflakeServer::start().await;
+        server
+            .mount_statement(MockStatement::new("handle-1").pending_polls(u64::MAX))
+            .await;
+        server
+    }
+
+    async fn cancel_requests(server: &MockSnowflakeServer) -> usize {
+        server.requests_to("/handle-1/cancel").await.len()
+    }
+
+    #[test]
+    fn statement_timeout_rounds_up_to_seconds() {
+        let config = SnowflakeConfig {
+            query_timeout: 300,
+            ..Default::default()
+        };
+        let client = SnowflakeClient::new(&config, test_auth("peerdb")).unwrap();
+        let timeout_secs = |timeout| {
+            let options = ExecuteOptions::default().with_timeout(timeout);
+            client.timeout_secs(&options)
+        };
+        assert_eq!(client.timeout_secs(&ExecuteOptions::default()), 300);
+        assert_eq!(timeout_secs(Duration::from_secs(30)), 30);
+        assert_eq!(timeout_secs(Duration::from_millis(1500)), 2);
+    }
+
+    #[test]
+    fn builds_api_host_from_account_identifier() {
+        for (raw_account, host) in [
+            // account locator, in the default region and qualified with region and cloud
+            ("xy12345", "xy12345.snowflakecomputing.com"),
+            (
+                "xy12345.us-east-2",
+                "xy12345.us-east-2.snowflakecomputing.com",
+            ),
+            (
+                "XY12345.us-east-1.aws",
+                "xy12345.us-east-1.aws.snowflakecomputing.com",
+            ),
+            (
+                "xy12345.us-east-1.privatelink",
+                "xy12345.us-east-1.privatelink.snowflakecomputing.com",
+            ),
+            // organization and account name
+            ("myorg-myaccount", "myorg-myaccount.snowflakecomputing.com"),
+            (
+                "myorg-my_account",
+                "myorg-my-account.snowflakecomputing.com",
+            ),
+            // replication
+            (
+                "xy12345-abcdefg.global",
+                "xy12345-abcdefg.global.snowflakecomputing.com",
+            ),
+            // a URL pasted as the identifier
+            (
+                " https://myorg-myaccount.snowflakecomputing.com/ ",
+                "myorg-myaccount.snowflakecomputing.com",
+            ),
+        ] {
+            assert_eq!(parse_account_host(raw_account), host, "{raw_account}");
+        }
+    }
+
+    #[test]
+    fn wraps_describe_queries() {
+        assert_eq!(
+            describe_query(" SELECT a, b FROM t;\n"),
+            "SELECT * FROM (\nSELECT a, b FROM t\n) WHERE 1=0"
+        );
+    }
+
+    #[test]
+    fn omits_unset_session_fields() {
+        let config = SnowflakeConfig {
+            database: "ANALYTICS".to_string(),
+            ..Default::default()
+        };
+        let options = ExecuteOptions::default();
+
+        let client = SnowflakeClient::new(&config, test_auth("peerdb")).unwrap();
+        let request = serde_json::to_value(client.statement_request("SELECT 1", &options)).unwrap();
+        assert_eq!(request["database"], "ANALYTICS");
+        for field in ["schema", "warehouse", "role"] {
+            assert!(request.get(field).is_none(), "{field} should be omitted");
+        }
+        assert!(request["parameters"].get("query_tag").is_none());
+
+        let client = client
+            .with_role("LOADER")
+            .with_warehouse("COMPUTE_WH")
+            .with_query_tag("peerdb");
+        let request = serde_json::to_value(client.statement_request("SELECT 1", &options)).unwrap();
+        assert_eq!(request["role"], "LOADER");
+        assert_eq!(request["warehouse"], "COMPUTE_WH");
+        assert_eq!(request["parameters"]["query_tag"], "peerdb");
+
+        let client = client.with_timezone(chrono_tz::Europe::Berlin);
+        let request = serde_json::to_value(client.statement_request("SELECT 1", &options)).unwrap();
+        assert_eq!(request["parameters"]["timezone"], "Europe/Berlin");
+    }
+
+    #[tokio::test]
+    async fn identifies_the_client_application() {
+        let server = MockSnowflakeServer::start().await;
+        server
+            .mount_statement(MockStatement::new("handle-1").column("ONE", "fixed"))
+            .await;
+
+        server.client().execute("SELECT 1").await.unwrap();
+        server
+            .client()
+            .with_application_id("PeerDB-staging")
+            .execute("SELECT 1")
+            .await
+            .unwrap();
+
+        let submissions = server.requests_to(STATEMENTS_PATH).await;
+        let applications: Vec<_> = submissions
+            .iter()
+            .map(|request| {
+                let user_agent = request.headers.get(header::USER_AGENT).unwrap();
+                assert_eq!(user_agent, USER_AGENT);
+                let body: serde_json::Value = request.body_json().unwrap();
+                body["parameters"]["CLIENT_APPLICATION"].clone()
+            })
+            .collect();
+        assert_eq!(applications, ["PeerDB", "PeerDB-staging"]);
+    }
+
+    #[test]
+    fn overrides_base_url() {
+        let client =
+            || SnowflakeClient::new(&SnowflakeConfig::default(), test_auth("peerdb")).unwrap();
+        let privatelink = "https://xy12345.us-east-1.privatelink.snowflakecomputing.com";
+        for base_url in [privatelink.to_string(), format!("{privatelink}/")] {
+            let client = client().with_base_url(&base_url).unwrap();
+            assert_eq!(
+                client.endpoint_url,
+                format!("{privatelink}/api/v2/statements")
+            );
+        }
+
+        for base_url in [
+            "http://xy12345.privatelink.snowflakecomputing.com",
+            "xy12345.privatelink.snowflakecomputing.com",
+            "https://xy12345.privatelink.snowflakecomputing.com/api/v2/statements",
+            "https://xy12345.privatelink.snowflakecomputing.com?x=1",
+        ] {
+            assert!(
+                matches!(
+                    client().with_base_url(base_url),
+                    Err(SnowflakeError::InvalidBaseUrl { .. })
+                ),
+                "{base_url}"
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn counts_statement_outcomes() {
+        let server = MockSnowflakeServer::start().await;
+        server
+            .mount_statement(MockStatement::new("handle-1").bytes_scanned(4096))
+            .await;
+        let client = server.client();
+        client.execute("SELECT 1").await.unwrap();
+        client.execute("SELECT 1").await.unwrap();
+        server.fail_next("POST", "/api/v2/statements", 400, 1).await;
+        client.execute("SELEC 1").await.unwrap_err();
+
+        assert_eq!(
+            client.metrics(),
+            SnowflakeMetricsSnapshot {
+                submitted: 3,
+                succeeded: 2,
+                failed_user: 1,
+                bytes_scanned: 8192,
+                ..Default::default()
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn times_out_stalled_requests() {
+        let server = MockSnowflakeServer::start().await;
+        server.mount_statement(MockStatement::new("handle-1")).await;
+        server
+            .delay_next("POST", "/api/v2/statements", Duration::from_secs(5))
+            .await;
+        let client = server
+            .client()
+            .with_retry_policy(RetryPolicy::never())
+            .with_http_timeouts(HttpTimeouts {
+                read: Duration::from_millis(100),
+                ..Default::default()
+            })
+            .unwrap();
+
+        let err = client.execute("SELECT 1").await.unwrap_err();
+        assert!(
+            matches!(err.inner(), SnowflakeError::RequestTimeout(_)),
+            "{err:?}"
+        );
+        assert!(err.is_transient());
+    }
+
+    #[tokio::test]
+    async fn resubmits_with_the_same_request_id() {
+        let server = MockSnowflakeServer::start().await;
+        server
+            .mount_statement(MockStatement::new("handle-1").column("ONE", "fixed"))
+            .await;
+        server
+            .delay_next("POST", "/api/v2/statements", Duration::from_secs(5))
+            .await;
+        let client = server
+            .client()
+            .with_retry_policy(RetryPolicy {
+                base_delay: Duration::from_millis(1),
+                ..Default::default()
+            })
+            .with_http_timeouts(HttpTimeouts {
+                read: Duration::from_millis(100),
+                ..Default::default()
+            })
+            .unwrap();
+
+        let result_set = client.execute("SELECT 1").await.unwrap();
+        let submissions: Vec<_> = server
+            .requests_to("/api/v2/statements")
+            .await
+            .iter()
+            .map(|request| {
+                let query: BTreeMap<_, _> = request.url.query_pairs().into_owned().collect();
+                (query["requestId"].clone(), query["retry"].clone())
+            })
+            .collect();
+        let request_id = result_set.request_id().to_string();
+        assert_eq!(
+            submissions,
+            [
+                (request_id.clone(), "false".to_string()),
+                (request_id, "true".to_string())
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn fails_statements_rejected_while_polling() {
+        let server = running_statement_server().await;
+        server
+            .fail_next("GET", "/api/v2/statements/handle-1", 400, u64::MAX)
+            .await;
+        let client = server.client().with_poll_policy(crate::mock::fast_polls());
+
+        let err = client.execute("SELECT 1").await.unwrap_err();
+        assert!(err.to_string().contains("400"), "{err}");
+        let submissions = server.requests_to("/api/v2/statements").await;
+        assert_eq!(submissions.len(), 1);
+        assert_eq!(server.requests_to("/handle-1").await.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn gives_up_polling_after_max_wait() {
+        let server = running_statement_server().await;
+        let client = server.client().with_poll_policy(PollPolicy {
+            max_wait: Some(Duration::from_millis(50)),
+            ..crate::mock::fast_polls()
+        });
+
+        let err = client.execute("SELECT 1").await.unwrap_err();
+        let SnowflakeError::Timeout { statement_handle } = err.inner() else {
+            panic!("expected a timeout, got {err:?}");
+        };
+        assert_eq!(statement_handle, "handle-1");
+        assert!(server.requests_to("/handle-1").await.len() > 1);
+    }
+
+    #[tokio::test]
+    async fn times_out_whole_operation() {
+        let server = running_statement_server().await;
+        let client = server
+            .client()
+            .with_http_timeouts(HttpTimeouts {
+                total: Some(Duration::from_millis(200)),
+                ..Default::default()
+            })
+            .unwrap();
+
+        let err = client.execute("SELECT 1").await.unwrap_err();
+        assert!(
+            matches!(err.inner(), SnowflakeError::OperationTimeout { .. }),
+            "{err:?}"
+        );
+        assert!(!err.is_transient());
+    }
+
+    #[tokio::test]
+    async fn runs_statements_in_one_request() {
+        let server = MockSnowflakeServer::start().await;
+        server
+            .mount_statement(
+                MockStatement::new("batch-1")
+                    .column("multiple statement execution", "text")
+                    .partition(vec![vec![Some(
+                        "Multiple statements executed successfully.".to_string(),
+                    )]])
+                    .children(&["child-1", "child-2"]),
+            )
+            .await;
+        for (handle, value) in [("child-1", "created"), ("child-2", "1")] {
+            server
+                .mount_statement(
+                    MockStatement::new(handle)
+                        .column("status", "text")
+                        .partition(vec![vec![Some(value.to_string())]]),
+                )
+                .await;
+        }
+        let client = server.client();
+
+        let result_sets = client
+            .execute_batch(&["CREATE TABLE t (id INT);", "SELECT 1"])
+            .await
+            .unwrap();
+        let handles: Vec<_> = result_sets.iter().map(|rs| rs.statement_handle()).collect();
+        assert_eq!(handles, ["child-1", "child-2"]);
+        assert_eq!(result_sets[1].rows(), &[vec![Some("1".to_string())]]);
+
+        let submissions = server.requests_to("/api/v2/statements").await;
+        assert_eq!(submissions.len(), 1);
+        let body: serde_json::Value = submissions[0].body_json().unwrap();
+        assert_eq!(body["statement"], "CREATE TABLE t (id INT);\nSELECT 1");
+        assert_eq!(body["parameters"]["MULTI_STATEMENT_COUNT"], "2");
+    }
+
+    #[tokio::test]
+    async fn rejects_empty_batches() {
+        let server = MockSnowflakeServer::start().await;
+
+        let err = server.client().execute_batch(&[]).await.unwrap_err();
+        assert!(matches!(err, SnowflakeError::EmptyBatch), "{err:?}");
+        assert!(server.requests_to(STATEMENTS_PATH).await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn fails_batches_missing_results() {
+        let server = MockSnowflakeServer::start().await;
+        server
+            .mount_statement(MockStatement::new("batch-1").children(&["child-1"]))
+            .await;
+        server.mount_statement(MockStatement::new("child-1")).await;
+
+        let err = server
+            .client()
+            .execute_batch(&["SELECT 1", "SELECT 2"])
+            .await
+            .unwrap_err();
+        assert!(
+            matches!(
+                err.inner(),
+                SnowflakeError::BatchMismatch {
+                    statements: 2,
+                    results: 1
+                }
+            ),
+            "{err:?}"
+        );
+    }
+
+    #[tokio::test]
+    async fn reports_query_stats() {
+        let server = MockSnowflakeServer::start().await;
+        server
+            .mount_statement(
+                MockStatement::new("handle-1")
+                    .column("ID", "fixed")
+                    .partition(vec![vec![Some("1".to_string())]; 2])
+                    .partition(vec![vec![Some("3".to_string())]])
+                    .bytes_scanned(1024),
+            )
+            .await;
+
+        let result_set = server.client().execute("SELECT id FROM t").await.unwrap();
+        assert_eq!(
+            result_set.stats(),
+            QueryStats {
+                bytes_scanned: 1024,
+                rows_produced: 3,
+                partition_count: 2,
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn gzips_large_request_bodies() {
+        let server = MockSnowflakeServer::start().await;
+        server.mount_statement(MockStatement::new("handle-1")).await;
+        let client = server.client();
+        let values = vec!["('row')"; 20_000].join(", ");
+        let large = format!("INSERT INTO t VALUES {values}");
+        client.execute("SELECT 1").await.unwrap();
+        client.execute(&large).await.unwrap();
+
+        let submissions = server.requests_to("/api/v2/statements").await;
+        let content_encoding =
+            |request: &wiremock::Request| request.headers.get(header::CONTENT_ENCODING).cloned();
+        assert_eq!(content_encoding(&submissions[0]), None);
+        assert_eq!(content_encoding(&submissions[1]).unwrap(), "gzip");
+        assert!(submissions[1].body.len() < large.len() / 10);
+
+        let mut json = String::new();
+        std::io::Read::read_to_string(
+            &mut flate2::read::GzDecoder::new(submissions[1].body.as_slice()),
+            &mut json,
+        )
+        .unwrap();
+        let body: serde_json::Value = serde_json::from_str(&json).unwrap();
+        assert_eq!(body["statement"], large);
+    }
+
+    #[tokio::test]
+    async fn cancel_posts_to_cancel_endpoint() {
+        let server = running_statement_server().await;
+        server.client().cancel("handle-1").await.unwrap();
+        assert_eq!(cancel_requests(&server).await, 1);
+    }
+
+    #[tokio::test]
+    async fn cancels_statement_when_token_fires() {
+        let server = running_statement_server().await;
+        let client = server.client();
+        let cancellation = CancellationToken::new();
+
+        tokio::spawn({
+            let cancellation = cancellation.clone();
+            async move {
+                tokio::time::sleep(Duration::from_millis(50)).await;
+                cancellation.cancel();
+            }
+        });
+        let err = client
+            .execute_cancellable("SELECT 1", cancellation)
+            .await
+            .unwrap_err();
+        assert!(matches!(err.inner(), SnowflakeError::Cancelled), "{err:?}");
+
+        for _ in 0..50 {
+            if cancel_requests(&server).await > 0 {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        assert_eq!(cancel_requests(&server).await, 1);
+    }
+
+    #[tokio::test]
+    async fn dropping_execute_cancels_statement() {
+        let server = running_statement_server().await;
+        let client = server.client();
+
+        let execute = client.execute("SELECT 1");
+        assert!(
+            tokio::time::timeout(Duration::from_millis(200), execute)
+                .await
+                .is_err()
+        );
+
+        // the cancel is sent from a spawned task
+        for _ in 0..50 {
+            if cancel_requests(&server).await > 0 {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        assert_eq!(cancel_requests(&server).await, 1);
+    }
+
+    #[tokio::test]
+    async fn reports_progress_while_polling() {
+        let server = MockSnowflakeServer::start().await;
+        server
+            .mount_statement(MockStatement::new("handle-1").pending_polls(2))
+            .await;
+        let client = server.client();
+
+        let polls = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let on_progress: ProgressCallback = {
+            let polls = polls.clone();
+            Arc::new(move |progress: &StatementProgress| {
+                assert_eq!(progress.statement_handle, "handle-1");
+                polls
+                    .lock()
+                    .unwrap()
+                    .push((progress.poll, progress.elapsed));
+            })
+        };
+        let options = ExecuteOptions::default().with_progress(on_progress);
+        client
+            .execute_with_options("SELECT 1", &options)
+            .await
+            .unwrap();
+
+        let polls = polls.lock().unwrap();
+        assert_eq!(
+            polls.iter().map(|(poll, _)| *poll).collect::<Vec<_>>(),
+            [1, 2]
+        );
+        assert!(polls[0].1 <= polls[1].1);
+    }
+
+    #[tokio::test]
+    async fn checks_jwt_expiry_against_the_local_clock() {
+        use crate::{Clock, SystemClock};
+
+        // signs JWTs that expired an hour ago by the system clock
+        struct SlowClock;
+        impl Clock for SlowClock {
+            fn now_unix(&self) -> u64 {
+                SystemClock.now_unix() - 7200
+            }
+        }
+        let server = MockSnowflakeServer::start().await;
+        server.mount_statement(MockStatement::new("handle-1")).await;
+        let auth = Arc::into_inner(test_auth("peerdb"))
+            .unwrap()
+            .with_clock(SlowClock)
+            .unwrap();
+        let client = SnowflakeClient::new(&SnowflakeConfig::default(), Arc::new(auth))
+            .unwrap()
+            .with_endpoint_url(server.endpoint_url())
+            .with_poll_policy(crate::mock::fast_polls());
+
+        client.execute("SELECT 1").await.unwrap();
+        let client = client.with_jwt_expiry_check(JwtExpiryCheck::Warn);
+        client.execute("SELECT 1").await.unwrap();
+        assert_eq!(server.requests_to("/api/v2/statements").await.len(), 2);
+
+        let client = client.with_jwt_expiry_check(JwtExpiryCheck::Error);
+        let err = client.execute("SELECT 1").await.unwrap_err();
+        assert!(
+            matches!(
+                err.inner(),
+                SnowflakeError::Auth(SnowflakeAuthError::JwtExpiredLocally(_))
+            ),
+            "{err:?}"
+        );
+        assert_eq!(server.requests_to("/api/v2/statements").await.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn asks_snowflake_to_abort_detached_statements() {
+        let server = MockSnowflakeServer::start().await;
+        server.mount_statement(MockStatement::new("handle-1")).await;
+
+        server.client().execute("SELECT 1").await.unwrap();
+        server
+            .client()
+            .with_abort_detached_query(false)
+            .execute("SELECT 1")
+            .await
+            .unwrap();
+
+        let submissions = server.requests_to(STATEMENTS_PATH).await;
+        let aborts: Vec<_> = submissions
+            .iter()
+            .map(|request| {
+                let body: serde_json::Value = request.body_json().unwrap();
+                body["parameters"]["ABORT_DETACHED_QUERY"].clone()
+            })
+            .collect();
+        assert_eq!(aborts, ["true", "false"]);
+    }
+
+    #[tokio::test]
+    async fn decodes_binary_in_the_requested_format() {
+        let server = MockSnowflakeServer::start().await;
+        server
+            .mount_statement(
+                MockStatement::new("handle-1")
+                    .column("B", "binary")
+                    .partition(vec![vec![Some("yv4=".to_string())]]),
+            )
+            .await;
+
+        let result_set = server
+            .client()
+            .with_binary_output_format(BinaryFormat::Base64)
+            .execute("SELECT b FROM t")
+            .await
+            .unwrap();
+        assert_eq!(
+            result_set.decode_row(&result_set.rows()[0]).unwrap(),
+            [SnowflakeValue::Binary(vec![0xca, 0xfe])]
+        );
+        server.client().execute("SELECT b FROM t").await.unwrap();
+        let formats: Vec<_> = server
+            .requests_to(STATEMENTS_PATH)
+            .await
+            .iter()
+            .map(|request| {
+                let body: serde_json::Value = request.body_json().unwrap();
+                body["parameters"]["binary_output_format"].clone()
+            })
+            .collect();
+        assert_eq!(formats, ["BASE64", "HEX"]);
+    }
+
+    #[tokio::test]
+    async fn sends_requests_through_a_given_http_client() {
+        let server = MockSnowflakeServer::start().await;
+        server.mount_statement(MockStatement::new("handle-1")).await;
+        let mut headers = header::HeaderMap::new();
+        headers.insert("x-proxy-tenant", header::HeaderValue::from_static("peerdb"));
+        let reqwest_client = SnowflakeClient::http_client_builder()
+            .default_headers(headers)
+            .build()
+            .unwrap();
+        let client = SnowflakeClient::with_http_client(
+            &SnowflakeConfig::default(),
+            test_auth("peerdb"),
+            reqwest_client,
+        )
+        .with_endpoint_url(server.endpoint_url())
+        .with_poll_policy(crate::mock::fast_polls());
+
+        client.execute("SELECT 1").await.unwrap();
+        let requests = server.requests_to("").await;
+        assert!(!requests.is_empty());
+        for request in requests {
+            assert_eq!(request.headers["x-proxy-tenant"], "peerdb");
+            assert_eq!(request.headers[header::USER_AGENT], USER_AGENT);
+        }
+    }
+
+    #[tokio::test]
+    async fn pins_output_formats() {
+        let server = MockSnowflakeServer::start().await;
+        server.mount_statement(MockStatement::new("handle-1")).await;
+
+        server.client().execute("SELECT 1").await.unwrap();
+        let submissions = server.requests_to(STATEMENTS_PATH).await;
+        let body: serde_json::Value = submissions[0].body_json().unwrap();
+        let parameters = &body["parameters"];
+        assert_eq!(parameters["date_output_format"], "YYYY/MM/DD");
+        assert_eq!(parameters["time_output_format"], "HH24:MI:SS.FF");
+        assert_eq!(
+            parameters["timestamp_ntz_output_format"],
+            "YYYY-MM-DDTHH24:MI:SS.FF"
+        );
+        for parameter in [
+            "timestamp_output_format",
+            "timestamp_ltz_output_format",
+            "timestamp_tz_output_format",
+        ] {
+            assert_eq!(
+                parameters[parameter], "YYYY-MM-DDTHH24:MI:SS.FFTZHTZM",
+                "{parameter}"
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn overrides_the_warehouse_per_statement() {
+        let server = MockSnowflakeServer::start().await;
+        server.mount_statement(MockStatement::new("handle-1")).await;
+        let client = server.client().with_warehouse("COMPUTE_WH");
+
+        client.execute("SELECT 1").await.unwrap();
+        let options = ExecuteOptions::default()
+            .with_warehouse("ANALYTICS_XL")
+            .unwrap();
+        client
+            .execute_with_options("SELECT 1", &options)
+            .await
+            .unwrap();
+
+        let submissions = server.requests_to(STATEMENTS_PATH).await;
+        let warehouses: Vec<_> = submissions
+            .iter()
+            .map(|request| request.body_json::<serde_json::Value>().unwrap()["warehouse"].clone())
+            .collect();
+        assert_eq!(warehouses, ["COMPUTE_WH", "ANALYTICS_XL"]);
+
+        let err = ExecuteOptions::default()
+            .with_warehouse("XL; DROP TABLE users")
+            .unwrap_err();
+        assert!(
+            matches!(
+                err,
+                SnowflakeError::InvalidIdentifier {
+                    kind: "warehouse",
+                    ..
+                }
+            ),
+            "{err:?}"
+        );
+    }
+
+    #[tokio::test]
+    async fn overrides_the_database_and_schema_per_statement() {
+        let server = MockSnowflakeServer::start().await;
+        server.mount_statement(MockStatement::new("handle-1")).await;
+        let config = SnowflakeConfig {
+            database: "PROD".to_string(),
+            ..Default::default()
+        };
+        let client = Arc::new(
+            SnowflakeClient::new(&config, test_auth("peerdb"))
+                .unwrap()
+                .with_endpoint_url(server.endpoint_url())
+                .with_poll_policy(crate::mock::fast_polls()),
+        );
+
+        let statements =
+            [("STAGING", "RAW"), ("ANALYTICS", "\"Marts\"")].map(|(database, schema)| {
+                let client = client.clone();
+                let options = ExecuteOptions::default()
+                    .with_database(database)
+                    .unwrap()
+                    .with_schema(schema)
+                    .unwrap();
+                tokio::spawn(async move { client.execute_with_options("SELECT 1", &options).await })
+            });
+        for statement in statements {
+            statement.await.unwrap().unwrap();
+        }
+        client.execute("SELECT 1").await.unwrap();
+
+        let mut contexts: Vec<_> = server
+            .requests_to(STATEMENTS_PATH)
+            .await
+            .iter()
+            .map(|request| {
+                let body: serde_json::Value = request.body_json().unwrap();
+                (body["database"].clone(), body["schema"].clone())
+            })
+            .collect();
+        let last = contexts.pop().unwrap();
+        // the client's database, and none of the statements' schemas
+        assert_eq!(last, (serde_json::json!("PROD"), serde_json::Value::Null));
+        contexts.sort_by_key(|context| context.0.to_string());
+        assert_eq!(
+            contexts,
+            [
+                (
+                    serde_json::json!("ANALYTICS"),
+                    serde_json::json!("\"Marts\"")
+                ),
+                (serde_json::json!("STAGING"), serde_json::json!("RAW")),
+            ]
+        );
+
+        for err in [
+            ExecuteOptions::default()
+                .with_database("PROD.PUBLIC")
+                .unwrap_err(),
+            ExecuteOptions::default().with_schema("").unwrap_err(),
+        ] {
+            assert!(
+                matches!(err, SnowflakeError::InvalidIdentifier { .. }),
+                "{err:?}"
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn reports_the_query_id_apart_from_the_handle() {
+        let server = MockSnowflakeServer::start().await;
+        server.mount_statement(MockStatement::new("handle-1")).await;
+        let result_set = server.client().execute("SELECT 1").await.unwrap();
+        assert_eq!(result_set.query_id(), "handle-1");
+
+        let server = MockSnowflakeServer::start().await;
+        server
+            .mount_statement(MockStatement::new("handle-1").query_id("01b2c3d4-0000-2222"))
+            .await;
+        let result_set = server.client().execute("SELECT 1").await.unwrap();
+        assert_eq!(result_set.statement_handle(), "handle-1");
+        assert_eq!(result_set.query_id(), "01b2c3d4-0000-2222");
+    }
+
+    #[tokio::test]
+    async fn waits_for_resuming_warehouses() {
+        let resuming = || {
+            serde_json::json!({
+                "code": "000606",
+                "message": "Warehouse 'COMPUTE_WH' cannot be resumed yet.",
+            })
+        };
+        let server = MockSnowflakeServer::start().await;
+        server.mount_statement(MockStatement::new("handle-1")).await;
+        let client = || server.client().with_retry_policy(RetryPolicy::never());
+
+        server
+            .fail_next_with("POST", STATEMENTS_PATH, 422, resuming())
+            .await;
+        let err = client().execute("SELECT 1").await.unwrap_err();
+        assert!(err.is_warehouse_resuming(), "{err:?}");
+
+        for _ in 0..2 {
+            server
+                .fail_next_with("POST", STATEMENTS_PATH, 422, resuming())
+                .await;
+        }
+        let client = client().with_wait_for_warehouse_resume(Duration::from_secs(5));
+        client.execute("SELECT 1").await.unwrap();
+        // one submission for the first statement, three for the second
+        assert_eq!(server.requests_to(STATEMENTS_PATH).await.len(), 4);
+
+        // the wait is bounded
+        let client = client.with_wait_for_warehouse_resume(Duration::from_millis(10));
+        for _ in 0..10 {
+            server
+                .fail_next_with("POST", STATEMENTS_PATH, 422, resuming())
+                .await;
+        }
+        let err = client.execute("SELECT 1").await.unwrap_err();
+        assert!(err.is_warehouse_resuming(), "{err:?}");
+    }
+}
@@ -0,0 +1,216 @@
+//! Spreading the statements of one logical peer over several clients, e.g. one per warehouse.
+
+use std::{
+    hash::{DefaultHasher, Hash, Hasher},
+    sync::{
+        Arc, Mutex, MutexGuard, PoisonError,
+        atomic::{AtomicUsize, Ordering},
+    },
+    time::{Duration, Instant},
+};
+
+use crate::{CircuitState, ErrorClass, SnowflakeClient, SnowflakeError, SnowflakeResultSet};
+
+const DEFAULT_FAILURE_COOL_DOWN: Duration = Duration::from_secs(30);
+
+/// How [`SnowflakeClientSet`] picks the client for a statement.
+#[derive(Clone, Copy, Debug)]
+pub enum Routing<'a> {
+    /// The next client in turn.
+    RoundRobin,
+    /// The same client for the same key, e.g. a hash of the query or a shard key, so that
+    /// repeated statements hit a warm warehouse cache. Keys are spread by rendezvous hashing:
+    /// while a client is skipped, only its keys move to other clients.
+    Key(&'a str),
+}
+
+/// Several [`SnowflakeClient`]s for one logical peer, each usually running on its own
+/// warehouse, that statements are routed across. A client whose statement failed for a reason
+/// other than the statement itself, or whose circuit is open, is skipped for a cool-down; if
+/// every client is, statements go to the one they would have anyway.
+pub struct SnowflakeClientSet {
+    clients: Vec<Arc<SnowflakeClient>>,
+    // when each client failed last, parallel to `clients`
+    failed_at: Vec<Mutex<Option<Instant>>>,
+    next: AtomicUsize,
+    failure_cool_down: Duration,
+}
+
+impl SnowflakeClientSet {
+    /// # Panics
+    ///
+    /// If `clients` is empty.
+    pub fn new(clients: Vec<SnowflakeClient>) -> Self {
+        assert!(
+            !clients.is_empty(),
+            "a client set needs at least one client"
+        );
+        Self {
+            failed_at: clients.iter().map(|_| Mutex::new(None)).collect(),
+            clients: clients.into_iter().map(Arc::new).collect(),
+            next: AtomicUsize::new(0),
+            failure_cool_down: DEFAULT_FAILURE_COOL_DOWN,
+        }
+    }
+
+    /// How long a client is skipped after a failure, 30s by default.
+    pub fn with_failure_cool_down(mut self, failure_cool_down: Duration) -> Self {
+        self.failure_cool_down = failure_cool_down;
+        self
+    }
+
+    pub fn clients(&self) -> &[Arc<SnowflakeClient>] {
+        &self.clients
+    }
+
+    /// The client a statement routed by `routing` would run on now.
+    pub fn pick(&self, routing: Routing) -> &Arc<SnowflakeClient> {
+        &self.clients[self.pick_index(routing)]
+    }
+
+    /// Runs `sql` on the client picked by `routing`, returning that client as well since
+    /// [`SnowflakeResultSet::into_rows`] fetches the rest of the result through it.
+    pub async fn execute(
+        &self,
+        sql: &str,
+        routing: Routing<'_>,
+    ) -> Result<(Arc<SnowflakeClient>, SnowflakeResultSet), SnowflakeError> {
+        let index = self.pick_index(routing);
+        let client = &self.clients[index];
+        match client.execute(sql).await {
+            Ok(result_set) => Ok((client.clone(), result_set)),
+            Err(err) => {
+                if err.class() != ErrorClass::UserError {
+                    *self.lock_failed_at(index) = Some(Instant::now());
+                }
+                Err(err)
+            }
+        }
+    }
+
+    fn pick_index(&self, routing: Routing) -> usize {
+        let preference: Vec<usize> = match routing {
+            Routing::RoundRobin => {
+                let start = self.next.fetch_add(1, Ordering::Relaxed);
+                (0..self.clients.len())
+                    .map(|offset| (start + offset) % self.clients.len())
+                    .collect()
+            }
+            Routing::Key(key) => {
+                let mut ranked: Vec<(u64, usize)> = (0..self.clients.len())
+                    .map(|index| {
+                        let mut hasher = DefaultHasher::new();
+                        (key, index).hash(&mut hasher);
+                        (hasher.finish(), index)
+                    })
+                    .collect();
+                ranked.sort_unstable_by(|a, b| b.cmp(a));
+                ranked.into_iter().map(|(_, index)| index).collect()
+            }
+        };
+        preference
+            .iter()
+            .copied()
+            .find(|&index| self.is_available(index))
+            .unwrap_or(preference[0])
+    }
+
+    fn is_available(&self, index: usize) -> bool {
+        let cooling_down = self
+            .lock_failed_at(index)
+            .is_some_and(|failed_at| failed_at.elapsed() < self.failure_cool_down);
+        !cooling_down
+            && !matches!(
+                self.clients[index].circuit_state(),
+                CircuitState::Open { .. }
+            )
+    }
+
+    fn lock_failed_at(&self, index: usize) -> MutexGuard<'_, Option<Instant>> {
+        self.failed_at[index]
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        RetryPolicy,
+        mock::{MockSnowflakeServer, MockStatement},
+    };
+
+    async fn server() -> MockSnowflakeServer {
+        let server = MockSnowflakeServer::start().await;
+        server
+            .mount_statement(MockStatement::new("handle-1").column("ONE", "fixed"))
+            .await;
+        server
+    }
+
+    fn index_of(set: &SnowflakeClientSet, client: &Arc<SnowflakeClient>) -> usize {
+        set.clients()
+            .iter()
+            .position(|candidate| Arc::ptr_eq(candidate, client))
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn routes_by_key_or_in_turn() {
+        let servers = [server().await, server().await, server().await];
+        let set = SnowflakeClientSet::new(servers.iter().map(|server| server.client()).collect());
+
+        let picked = index_of(&set, set.pick(Routing::Key("tenant-42")));
+        for _ in 0..5 {
+            let (client, _) = set
+                .execute("SELECT 1", Routing::Key("tenant-42"))
+                .await
+                .unwrap();
+            assert_eq!(index_of(&set, &client), picked);
+        }
+        let keys: Vec<_> = (0..32).map(|n| format!("tenant-{n}")).collect();
+        let used: std::collections::BTreeSet<_> = keys
+            .iter()
+            .map(|key| index_of(&set, set.pick(Routing::Key(key))))
+            .collect();
+        assert_eq!(used.len(), 3, "keys should spread over every client");
+
+        let in_turn: Vec<_> = (0..4)
+            .map(|_| index_of(&set, set.pick(Routing::RoundRobin)))
+            .collect();
+        assert_eq!(in_turn, [0, 1, 2, 0]);
+    }
+
+    #[tokio::test]
+    async fn skips_failed_clients_for_a_while() {
+        let servers = [server().await, server().await];
+        servers[0]
+            .fail_next("POST", "/api/v2/statements", 503, 1)
+            .await;
+        let set = SnowflakeClientSet::new(
+            servers
+                .iter()
+                .map(|server| server.client().with_retry_policy(RetryPolicy::never()))
+                .collect(),
+        )
+        .with_failure_cool_down(Duration::from_millis(50));
+
+        let key = (0..)
+            .map(|n| format!("key-{n}"))
+            .find(|key| index_of(&set, set.pick(Routing::Key(key))) == 0)
+            .unwrap();
+        assert!(set.execute("SELECT 1", Routing::Key(&key)).await.is_err());
+        let (client, _) = set.execute("SELECT 1", Routing::Key(&key)).await.unwrap();
+        assert_eq!(index_of(&set, &client), 1);
+        assert_eq!(
+            (0..4)
+                .map(|_| index_of(&set, set.pick(Routing::RoundRobin)))
+                .collect::<Vec<_>>(),
+            [1; 4]
+        );
+
+        tokio::time::sleep(Duration::from_millis(60)).await;
+        assert_eq!(index_of(&set, set.pick(Routing::Key(&key))), 0);
+    }
+}
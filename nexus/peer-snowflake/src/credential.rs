@@ -0,0 +1,203 @@
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use jsonwebtoken::{Algorithm, DecodingKey, Validation};
+use reqwest::{RequestBuilder, header::AUTHORIZATION};
+use secrecy::{ExposeSecret, SecretString};
+use serde::Deserialize;
+use tokio::sync::RwLock;
+use tracing::info;
+
+use crate::auth::{SnowflakeAuth, SnowflakeAuthError};
+
+const TOKEN_TYPE_HEADER: &str = "X-Snowflake-Authorization-Token-Type";
+pub(crate) const KEYPAIR_JWT_TOKEN_TYPE: &str = "KEYPAIR_JWT";
+// refresh OAuth access tokens this long before the IdP says they expire
+const OAUTH_REFRESH_MARGIN_SECS: u64 = 60;
+
+/// A way of authenticating against the Snowflake SQL API.
+#[async_trait::async_trait]
+pub trait SnowflakeCredential: Send + Sync {
+    /// Value of the `X-Snowflake-Authorization-Token-Type` header.
+    fn token_type(&self) -> &'static str;
+
+    /// Value of the `Authorization` header, refreshing the underlying token if it is due.
+    async fn authorization_header(&self) -> Result<SecretString, SnowflakeAuthError>;
+
+    /// How long the cached token has left, without refreshing it. `None` if there is no cached
+    /// token (yet), or it can't be checked without waiting.
+    fn cached_lifetime(&self) -> Option<Duration> {
+        None
+    }
+
+    /// Called when Snowflake rejected the credentials of a statement, or they couldn't be
+    /// produced, e.g. to switch to other ones.
+    fn record_auth_failure(&self) {}
+
+    /// Called when a statement authorized by this credential succeeded.
+    fn record_success(&self) {}
+}
+
+/// Sets the authorization headers of `credential` on a Snowflake request.
+pub(crate) async fn authorize(
+    request: RequestBuilder,
+    credential: &dyn SnowflakeCredential,
+) -> Result<RequestBuilder, SnowflakeAuthError> {
+    let authorization = credential.authorization_header().await?;
+    Ok(request
+        .header(AUTHORIZATION, authorization.expose_secret())
+        .header(TOKEN_TYPE_HEADER, credential.token_type()))
+}
+
+/// Decodes `jwt` without verifying its signature, failing if its `exp` has passed by the local
+/// clock.
+pub(crate) fn check_jwt_expiry(jwt: &str) -> Result<(), jsonwebtoken::errors::Error> {
+    let mut validation = Validation::new(Algorithm::RS256);
+    validation.insecure_disable_signature_validation();
+    validation.validate_aud = false;
+    validation.leeway = 0;
+    jsonwebtoken::decode::<serde::de::IgnoredAny>(jwt, &DecodingKey::from_secret(&[]), &validation)
+        .map(|_| ())
+}
+
+#[async_trait::async_trait]
+impl SnowflakeCredential for SnowflakeAuth {
+    fn token_type(&self) -> &'static str {
+        KEYPAIR_JWT_TOKEN_TYPE
+    }
+
+    async fn authorization_header(&self) -> Result<SecretString, SnowflakeAuthError> {
+        let jwt = self.get_jwt()?;
+        Ok(SecretString::from(format!(
+            "Bearer {}",
+            jwt.expose_secret()
+        )))
+    }
+
+    fn cached_lifetime(&self) -> Option<Duration> {
+        // a lazy auth that hasn't signed yet has nothing cached
+        self.has_signed_jwt()
+            .then(|| Duration::from_secs(self.jwt_remaining_secs()))
+    }
+}
+
+#[derive(Deserialize)]
+struct OAuthTokenResponse {
+    access_token: String,
+    expires_in: u64,
+}
+
+struct OAuthToken {
+    access_token: SecretString,
+    expires_at: u64,
+}
+
+/// OAuth access tokens from an external IdP (or Snowflake's own OAuth),
+/// renewed through the token endpoint with a refresh token.
+pub struct SnowflakeOAuth {
+    token_endpoint: String,
+    client_id: String,
+    client_secret: SecretString,
+    refresh_token: SecretString,
+    reqwest_client: reqwest::Client,
+    token: RwLock<Option<OAuthToken>>,
+}
+
+impl SnowflakeOAuth {
+    pub fn new(
+        token_endpoint: String,
+        client_id: String,
+        client_secret: SecretString,
+        refresh_token: SecretString,
+    ) -> Self {
+        Self {
+            token_endpoint,
+            client_id,
+            client_secret,
+            refresh_token,
+            reqwest_client: reqwest::Client::new(),
+            token: RwLock::new(None),
+        }
+    }
+
+    fn now() -> u64 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or_default()
+    }
+
+    #[tracing::instrument(name = "peer_sflake::oauth_refresh_token", skip_all)]
+    async fn refresh_token(&self) -> Result<OAuthToken, SnowflakeAuthError> {
+        info!(
+            "Refreshing SnowFlake OAuth token for client: {}",
+            self.client_id
+        );
+        let requested_at = SnowflakeOAuth::now();
+        let response = self
+            .reqwest_client
+            .post(&self.token_endpoint)
+            .basic_auth(&self.client_id, Some(self.client_secret.expose_secret()))
+            .form(&[
+                ("grant_type", "refresh_token"),
+                ("refresh_token", self.refresh_token.expose_secret()),
+            ])
+            .send()
+            .await?
+            .error_for_status()?
+            .json::<OAuthTokenResponse>()
+            .await?;
+
+        Ok(OAuthToken {
+            access_token: SecretString::from(response.access_token),
+            expires_at: requested_at + response.expires_in,
+        })
+    }
+
+    fn usable(token: &Option<OAuthToken>, now: u64) -> Option<SecretString> {
+        token
+            .as_ref()
+            .filter(|token| now + OAUTH_REFRESH_MARGIN_SECS < token.expires_at)
+            .map(|token| token.access_token.clone())
+    }
+}
+
+#[async_trait::async_trait]
+impl SnowflakeCredential for SnowflakeOAuth {
+    fn token_type(&self) -> &'static str {
+        "OAUTH"
+    }
+
+    fn cached_lifetime(&self) -> Option<Duration> {
+        let token = self.token.try_read().ok()?;
+        let expires_at = token.as_ref()?.expires_at;
+        Some(Duration::from_secs(
+            expires_at.saturating_sub(SnowflakeOAuth::now()),
+        ))
+    }
+
+    async fn authorization_header(&self) -> Result<SecretString, SnowflakeAuthError> {
+        let now = SnowflakeOAuth::now();
+        // bound separately so the read guard is released before taking the write lock
+        let cached = SnowflakeOAuth::usable(&*self.token.read().await, now);
+        let access_token = match cached {
+            Some(access_token) => access_token,
+            None => {
+                let mut token = self.token.write().await;
+                // another caller may have refreshed while we were waiting on the write lock
+                match SnowflakeOAuth::usable(&token, now) {
+                    Some(access_token) => access_token,
+                    None => {
+                        let refreshed = self.refresh_token().await?;
+                        let access_token = refreshed.access_token.clone();
+                        *token = Some(refreshed);
+                        access_token
+                    }
+                }
+            }
+        };
+        Ok(SecretString::from(format!(
+            "Bearer {}",
+            access_token.expose_secret()
+        )))
+    }
+}
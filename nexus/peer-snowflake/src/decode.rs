@@ -0,0 +1,707 @@
+//! Decoding of the cell values in SQL API responses.
+//!
+//! Snowflake sends every cell as a JSON string (or `null`) and describes the columns in
+//! `resultSetMetaData.rowType`. Date and time types come back as numbers relative to the Unix
+//! epoch rather than in a display format:
+//!
+//! * `DATE`: whole days since 1970-01-01, e.g. `"19358"`.
+//! * `TIME`: seconds since midnight with up to 9 fractional digits, e.g. `"49322.123456789"`.
+//! * `TIMESTAMP_NTZ` / `TIMESTAMP_LTZ`: seconds since the epoch with up to 9 fractional
+//!   digits, e.g. `"1625097600.123456789"`; negative for instants before 1970. NTZ values are
+//!   wall-clock times, LTZ values are instants in UTC.
+//! * `TIMESTAMP_TZ`: the same seconds since the epoch, a space, then the UTC offset in minutes
+//!   plus 1440 (so that it is never negative), e.g. `"1625097600.000000000 1560"` for +02:00.
+//! * Any of the timestamps may also come as the structured `[epoch, fraction, tz]` triple of
+//!   whole seconds, nanoseconds added to them and the biased offset, e.g.
+//!   `"[-1, 500000000, 1440]"` for half a second before the epoch in UTC. The offset is optional
+//!   and only kept for `TIMESTAMP_TZ`.
+//!
+//! The fractional part is parsed as digits rather than through `f64` so nanoseconds survive.
+//! Values in the `*_OUTPUT_FORMAT`s that [`SnowflakeClient`](crate::SnowflakeClient) sets on
+//! every statement are accepted too: `2021/06/30` for dates, `13:45:00.123456789` for times,
+//! and `2021-06-30T13:45:00.123456789`, with an offset such as `+0200` unless NTZ, for
+//! timestamps. A `TIMESTAMP_LTZ` formatted without an offset is a wall-clock time in the
+//! session `TIMEZONE`, and is converted to UTC from there.
+
+use chrono::{DateTime, Days, FixedOffset, NaiveDate, NaiveDateTime, NaiveTime, TimeZone, Utc};
+use chrono_tz::Tz;
+
+use base64::prelude::{BASE64_STANDARD, Engine as _};
+
+use crate::{client::ResultSetRowType, types::SnowflakeDataType};
+
+const NANOS_PER_SEC: i128 = 1_000_000_000;
+const TIMESTAMP_TZ_OFFSET_BIAS_MINUTES: i32 = 1440;
+
+const DATE_PARSE_FORMAT: &str = "%Y/%m/%d";
+const TIME_PARSE_FORMAT: &str = "%H:%M:%S%.f";
+const TIMESTAMP_PARSE_FORMAT: &str = "%FT%T%.f";
+const TIMESTAMP_TZ_PARSE_FORMAT: &str = "%FT%T%.f%z";
+
+/// A decoded Snowflake cell.
+#[derive(Clone, Debug, PartialEq)]
+pub enum SnowflakeValue {
+    Null,
+    /// `NUMBER(p, s)`: `value / 10^scale`, kept exact.
+    Number {
+        value: i128,
+        scale: u32,
+    },
+    Real(f64),
+    Text(String),
+    Boolean(bool),
+    Date(NaiveDate),
+    Time(NaiveTime),
+    TimestampNtz(NaiveDateTime),
+    TimestampLtz(DateTime<Utc>),
+    TimestampTz(DateTime<FixedOffset>),
+    Binary(Vec<u8>),
+    Variant(serde_json::Value),
+    /// A `GEOGRAPHY` or `GEOMETRY`, as the GeoJSON, WKT or WKB text of the session's
+    /// `GEOGRAPHY_OUTPUT_FORMAT` / `GEOMETRY_OUTPUT_FORMAT`.
+    Geo(String),
+}
+
+/// How `BINARY` values are spelled in responses, as set by the `BINARY_OUTPUT_FORMAT` session
+/// parameter, see [`crate::SnowflakeClient::with_binary_output_format`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum BinaryFormat {
+    /// Two hex digits per byte, e.g. `"CAFE"`.
+    #[default]
+    Hex,
+    /// Padded standard base64, e.g. `"yv4="`, a third shorter than hex for large values.
+    Base64,
+}
+
+impl BinaryFormat {
+    /// The value of `BINARY_OUTPUT_FORMAT` for this format.
+    pub(crate) fn parameter(self) -> &'static str {
+        match self {
+            BinaryFormat::Hex => "HEX",
+            BinaryFormat::Base64 => "BASE64",
+        }
+    }
+
+    pub(crate) fn decode(self, raw: &str) -> Result<Vec<u8>, String> {
+        match self {
+            BinaryFormat::Hex => hex::decode(raw).map_err(|e| format!("{e}")),
+            BinaryFormat::Base64 => BASE64_STANDARD.decode(raw).map_err(|e| format!("{e}")),
+        }
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+#[error("cannot decode {value:?} in column {column} as {data_type:?}: {reason}")]
+pub struct SnowflakeDecodeError {
+    pub column: String,
+    pub data_type: SnowflakeDataType,
+    pub value: String,
+    pub reason: String,
+}
+
+/// Decodes the cell `cell` of the column described by `row_type`, for a statement run with
+/// the session timezone `timezone` and `BINARY_OUTPUT_FORMAT` `binary_format`.
+pub(crate) fn decode_cell(
+    row_type: &ResultSetRowType,
+    cell: Option<&str>,
+    timezone: Tz,
+    binary_format: BinaryFormat,
+) -> Result<SnowflakeValue, SnowflakeDecodeError> {
+    let Some(raw) = cell else {
+        return Ok(SnowflakeValue::Null);
+    };
+    decode_raw(row_type, raw, timezone, binary_format).map_err(|reason| SnowflakeDecodeError {
+        column: row_type.name.clone(),
+        data_type: row_type.r#type,
+        value: raw.to_string(),
+        reason,
+    })
+}
+
+fn decode_raw(
+    row_type: &ResultSetRowType,
+    raw: &str,
+    timezone: Tz,
+    binary_format: BinaryFormat,
+) -> Result<SnowflakeValue, String> {
+    Ok(match row_type.r#type {
+        SnowflakeDataType::Fixed => {
+            let scale = row_type.scale.unwrap_or(0);
+            SnowflakeValue::Number {
+                value: parse_fixed(raw, scale)?,
+                scale,
+            }
+        }
+        SnowflakeDataType::Real => SnowflakeValue::Real(raw.parse().map_err(|e| format!("{e}"))?),
+        SnowflakeDataType::Text => SnowflakeValue::Text(raw.to_string()),
+        SnowflakeDataType::Binary => SnowflakeValue::Binary(binary_format.decode(raw)?),
+        SnowflakeDataType::Boolean => SnowflakeValue::Boolean(match raw {
+            "true" | "TRUE" | "1" => true,
+            "false" | "FALSE" | "0" => false,
+            _ => return Err("not a boolean".to_string()),
+        }),
+        SnowflakeDataType::Date => SnowflakeValue::Date(parse_date(raw)?),
+        SnowflakeDataType::Time => SnowflakeValue::Time(parse_time(raw)?),
+        SnowflakeDataType::TimestampNtz => {
+            SnowflakeValue::TimestampNtz(match parse_epoch_timestamp(raw) {
+                Ok((nanos, _)) => timestamp_from_nanos(nanos)?.naive_utc(),
+                Err(_) => NaiveDateTime::parse_from_str(raw, TIMESTAMP_PARSE_FORMAT)
+                    .map_err(|e| format!("{e}"))?,
+            })
+        }
+        SnowflakeDataType::TimestampLtz => {
+            SnowflakeValue::TimestampLtz(match parse_epoch_timestamp(raw) {
+                Ok((nanos, _)) => timestamp_from_nanos(nanos)?,
+                Err(_) => parse_timestamp_ltz_formatted(raw, timezone)?,
+            })
+        }
+        SnowflakeDataType::TimestampTz => SnowflakeValue::TimestampTz(parse_timestamp_tz(raw)?),
+        SnowflakeDataType::Variant | SnowflakeDataType::Object | SnowflakeDataType::Array => {
+            SnowflakeValue::Variant(parse_semi_structured(row_type.r#type, raw)?)
+        }
+        SnowflakeDataType::Geography | SnowflakeDataType::Geometry => {
+            SnowflakeValue::Geo(raw.to_string())
+        }
+        SnowflakeDataType::Unknown => return Err("unsupported Snowflake type".to_string()),
+    })
+}
+
+/// Parses the JSON text of a semi-structured value, checking that an `OBJECT` or `ARRAY` is one.
+fn parse_semi_structured(
+    data_type: SnowflakeDataType,
+    raw: &str,
+) -> Result<serde_json::Value, String> {
+    let value: serde_json::Value =
+        serde_json::from_str(raw).map_err(|e| format!("invalid JSON: {e}"))?;
+    match (data_type, &value) {
+        (SnowflakeDataType::Object, serde_json::Value::Object(_))
+        | (SnowflakeDataType::Array, serde_json::Value::Array(_))
+        | (SnowflakeDataType::Variant, _) => Ok(value),
+        (SnowflakeDataType::Object, _) => Err("not a JSON object".to_string()),
+        _ => Err("not a JSON array".to_string()),
+    }
+}
+
+/// Parses a decimal string into its value scaled by `10^scale`, e.g. `"-1.5"` at scale 2 is
+/// `-150`.
+fn parse_fixed(raw: &str, scale: u32) -> Result<i128, String> {
+    let (negative, digits) = match raw.strip_prefix('-') {
+        Some(digits) => (true, digits),
+        None => (false, raw),
+    };
+    let (integer, fraction) = digits.split_once('.').unwrap_or((digits, ""));
+    if fraction.len() > scale as usize {
+        return Err(format!("more than {scale} fractional digits"));
+    }
+    let overflow = || "out of range for NUMBER".to_string();
+    let mut value: i128 = 0;
+    for c in integer.chars().chain(fraction.chars()) {
+        let digit = c
+            .to_digit(10)
+            .ok_or_else(|| format!("invalid digit {c:?}"))?;
+        value = value
+            .checked_mul(10)
+            .and_then(|value| value.checked_add(digit.into()))
+            .ok_or_else(overflow)?;
+    }
+    if integer.is_empty() && fraction.is_empty() {
+        return Err("no digits".to_string());
+    }
+    // pad the fraction out to the column's scale
+    value = 10i128
+        .checked_pow(scale - fraction.len() as u32)
+        .and_then(|factor| value.checked_mul(factor))
+        .ok_or_else(overflow)?;
+    Ok(if negative { -value } else { value })
+}
+
+/// Parses `[-]seconds[.fraction]` into nanoseconds, keeping all 9 fractional digits.
+fn parse_epoch_nanos(raw: &str) -> Result<i128, String> {
+    let (negative, digits) = match raw.strip_prefix('-') {
+        Some(digits) => (true, digits),
+        None => (false, raw),
+    };
+    let (seconds, fraction) = digits.split_once('.').unwrap_or((digits, ""));
+    if seconds.is_empty()
+        || fraction.len() > 9
+        || !seconds
+            .bytes()
+            .chain(fraction.bytes())
+            .all(|b| b.is_ascii_digit())
+    {
+        return Err("not seconds since the epoch".to_string());
+    }
+    let seconds: i128 = seconds.parse().map_err(|e| format!("{e}"))?;
+    let nanos: i128 = if fraction.is_empty() {
+        0
+    } else {
+        format!("{fraction:0<9}")
+            .parse()
+            .map_err(|e| format!("{e}"))?
+    };
+    let total = seconds
+        .checked_mul(NANOS_PER_SEC)
+        .and_then(|total| total.checked_add(nanos))
+        .ok_or_else(|| "seconds since the epoch out of range".to_string())?;
+    Ok(if negative { -total } else { total })
+}
+
+/// Parses an epoch timestamp into nanoseconds since the epoch and the biased `TIMESTAMP_TZ`
+/// offset if there is one, from either `seconds[.fraction][ offset]` or the structured
+/// `[epoch, fraction, tz]` triple.
+fn parse_epoch_timestamp(raw: &str) -> Result<(i128, Option<i32>), String> {
+    let parse_offset = |offset: &str| {
+        offset
+            .parse::<i32>()
+            .map_err(|e| format!("invalid offset: {e}"))
+    };
+    if let Some(parts) = raw
+        .trim()
+        .strip_prefix('[')
+        .and_then(|raw| raw.strip_suffix(']'))
+    {
+        let parts: Vec<_> = parts
+            .split(',')
+            .map(|part| part.trim().trim_matches('"'))
+            .collect();
+        let (epoch, fraction, offset) = match parts[..] {
+            [epoch, fraction] => (epoch, fraction, None),
+            [epoch, fraction, offset] => (epoch, fraction, Some(parse_offset(offset)?)),
+            _ => return Err("expected [epoch, fraction, tz]".to_string()),
+        };
+        let epoch: i64 = epoch.parse().map_err(|e| format!("invalid epoch: {e}"))?;
+        let fraction: u32 = fraction
+            .parse()
+            .ok()
+            .filter(|&fraction| i128::from(fraction) < NANOS_PER_SEC)
+            .ok_or_else(|| "fraction is not a number of nanoseconds".to_string())?;
+        return Ok((
+            i128::from(epoch) * NANOS_PER_SEC + i128::from(fraction),
+            offset,
+        ));
+    }
+    match raw.split_once(' ') {
+        Some((epoch, offset)) => Ok((parse_epoch_nanos(epoch)?, Some(parse_offset(offset)?))),
+        None => Ok((parse_epoch_nanos(raw)?, None)),
+    }
+}
+
+fn timestamp_from_nanos(nanos: i128) -> Result<DateTime<Utc>, String> {
+    let seconds = i64::try_from(nanos.div_euclid(NANOS_PER_SEC)).map_err(|e| format!("{e}"))?;
+    let subsec = nanos.rem_euclid(NANOS_PER_SEC) as u32;
+    DateTime::from_timestamp(seconds, subsec).ok_or_else(|| "timestamp out of range".to_string())
+}
+
+fn parse_date(raw: &str) -> Result<NaiveDate, String> {
+    match raw.parse::<i64>() {
+        Ok(days) => {
+            let epoch = DateTime::UNIX_EPOCH.date_naive();
+            let delta = Days::new(days.unsigned_abs());
+            if days < 0 {
+                epoch.checked_sub_days(delta)
+            } else {
+                epoch.checked_add_days(delta)
+            }
+            .ok_or_else(|| "date out of range".to_string())
+        }
+        Err(_) => NaiveDate::parse_from_str(raw, DATE_PARSE_FORMAT).map_err(|e| format!("{e}")),
+    }
+}
+
+fn parse_time(raw: &str) -> Result<NaiveTime, String> {
+    match parse_epoch_nanos(raw) {
+        Ok(nanos) => {
+            let seconds = u32::try_from(nanos.div_euclid(NANOS_PER_SEC))
+                .map_err(|_| "time out of range".to_string())?;
+            let subsec = nanos.rem_euclid(NANOS_PER_SEC) as u32;
+            NaiveTime::from_num_seconds_from_midnight_opt(seconds, subsec)
+                .ok_or_else(|| "time out of range".to_string())
+        }
+        Err(_) => NaiveTime::parse_from_str(raw, TIME_PARSE_FORMAT).map_err(|e| format!("{e}")),
+    }
+}
+
+fn parse_timestamp_tz(raw: &str) -> Result<DateTime<FixedOffset>, String> {
+    let Ok((nanos, offset)) = parse_epoch_timestamp(raw) else {
+        return parse_timestamp_tz_formatted(raw);
+    };
+    // a triple without a tz is in UTC
+    let offset_minutes =
+        offset.unwrap_or(TIMESTAMP_TZ_OFFSET_BIAS_MINUTES) - TIMESTAMP_TZ_OFFSET_BIAS_MINUTES;
+    let offset = FixedOffset::east_opt(offset_minutes * 60)
+        .ok_or_else(|| "offset out of range".to_string())?;
+    Ok(timestamp_from_nanos(nanos)?.with_timezone(&offset))
+}
+
+// the output format ends in a +hhmm offset, with Z for UTC
+fn parse_timestamp_tz_formatted(raw: &str) -> Result<DateTime<FixedOffset>, String> {
+    let normalized = match raw.strip_suffix('Z') {
+        Some(prefix) => format!("{prefix}+0000"),
+        None => raw.to_string(),
+    };
+    DateTime::parse_from_str(&normalized, TIMESTAMP_TZ_PARSE_FORMAT).map_err(|e| format!("{e}"))
+}
+
+fn parse_timestamp_ltz_formatted(raw: &str, timezone: Tz) -> Result<DateTime<Utc>, String> {
+    if let Ok(ts) = parse_timestamp_tz_formatted(raw) {
+        return Ok(ts.to_utc());
+    }
+    let naive =
+        NaiveDateTime::parse_from_str(raw, TIMESTAMP_PARSE_FORMAT).map_err(|e| format!("{e}"))?;
+    // a wall-clock time repeated by a DST change resolves to its first occurrence
+    timezone
+        .from_local_datetime(&naive)
+        .earliest()
+        .map(|ts| ts.to_utc())
+        .ok_or_else(|| format!("{naive} does not exist in {timezone}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn column(data_type: SnowflakeDataType, scale: Option<u32>) -> ResultSetRowType {
+        ResultSetRowType {
+            name: "c".to_string(),
+            r#type: data_type,
+            scale,
+            precision: None,
+            nullable: Some(true),
+        }
+    }
+
+    fn decode(data_type: SnowflakeDataType, raw: &str) -> SnowflakeValue {
+        decode_cell(
+            &column(data_type, None),
+            Some(raw),
+            Tz::UTC,
+            BinaryFormat::Hex,
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn keeps_null_distinct_from_empty_text() {
+        let text = column(SnowflakeDataType::Text, None);
+        assert_eq!(
+            decode_cell(&text, None, Tz::UTC, BinaryFormat::Hex).unwrap(),
+            SnowflakeValue::Null
+        );
+        assert_eq!(
+            decode_cell(&text, Some(""), Tz::UTC, BinaryFormat::Hex).unwrap(),
+            SnowflakeValue::Text(String::new())
+        );
+    }
+
+    #[test]
+    fn decodes_numbers_at_column_scale() {
+        let cases = [
+            ("42", 0, 42),
+            ("-42", 0, -42),
+            ("1.5", 2, 150),
+            ("-0.25", 2, -25),
+            (
+                "12345678901234567890123456789012345678",
+                0,
+                12345678901234567890123456789012345678,
+            ),
+        ];
+        for (raw, scale, value) in cases {
+            let decoded = decode_cell(
+                &column(SnowflakeDataType::Fixed, Some(scale)),
+                Some(raw),
+                Tz::UTC,
+                BinaryFormat::Hex,
+            )
+            .unwrap();
+            assert_eq!(decoded, SnowflakeValue::Number { value, scale }, "{raw}");
+        }
+        assert!(
+            decode_cell(
+                &column(SnowflakeDataType::Fixed, Some(1)),
+                Some("1.25"),
+                Tz::UTC,
+                BinaryFormat::Hex
+            )
+            .is_err()
+        );
+    }
+
+    #[test]
+    fn decodes_scalars() {
+        assert_eq!(
+            decode(SnowflakeDataType::Real, "-1.5e3"),
+            SnowflakeValue::Real(-1500.0)
+        );
+        assert_eq!(
+            decode(SnowflakeDataType::Boolean, "true"),
+            SnowflakeValue::Boolean(true)
+        );
+        assert_eq!(
+            decode(SnowflakeDataType::Binary, "cafe"),
+            SnowflakeValue::Binary(vec![0xca, 0xfe])
+        );
+        assert_eq!(
+            decode(SnowflakeDataType::Variant, r#"{"a": [1, null]}"#),
+            SnowflakeValue::Variant(serde_json::json!({"a": [1, null]}))
+        );
+    }
+
+    #[test]
+    fn decodes_binary_in_either_format() {
+        let binary = column(SnowflakeDataType::Binary, None);
+        let decode_as = |raw: &str, format| decode_cell(&binary, Some(raw), Tz::UTC, format);
+        let large: Vec<u8> = (0..=255).cycle().take(1 << 20).collect();
+        let cases = [
+            (vec![0xca, 0xfe], "CAFE".to_string(), "yv4=".to_string()),
+            (Vec::new(), String::new(), String::new()),
+            (
+                large.clone(),
+                hex::encode(&large),
+                BASE64_STANDARD.encode(&large),
+            ),
+        ];
+        for (bytes, hex, base64) in cases {
+            for (raw, format) in [(hex, BinaryFormat::Hex), (base64, BinaryFormat::Base64)] {
+                assert_eq!(
+                    decode_as(&raw, format).unwrap(),
+                    SnowflakeValue::Binary(bytes.clone()),
+                    "{format:?}"
+                );
+            }
+        }
+        // the other format is rejected rather than decoded into the wrong bytes
+        assert!(decode_as("yv4=", BinaryFormat::Hex).is_err());
+        assert!(decode_as("CAF", BinaryFormat::Base64).is_err());
+    }
+
+    #[test]
+    fn decodes_semi_structured_values() {
+        assert_eq!(
+            decode(SnowflakeDataType::Variant, "\"text\""),
+            SnowflakeValue::Variant(serde_json::json!("text"))
+        );
+        assert_eq!(
+            decode(SnowflakeDataType::Object, "{\n  \"k\": \"v\\u00e9\"\n}"),
+            SnowflakeValue::Variant(serde_json::json!({"k": "v\u{e9}"}))
+        );
+        assert_eq!(
+            decode(SnowflakeDataType::Array, "[\n  1,\n  \"two\"\n]"),
+            SnowflakeValue::Variant(serde_json::json!([1, "two"]))
+        );
+
+        let mut payload = column(SnowflakeDataType::Variant, None);
+        payload.name = "PAYLOAD".to_string();
+        let err = decode_cell(
+            &payload,
+            Some("{\"a\": \"\\x41\"}"),
+            Tz::UTC,
+            BinaryFormat::Hex,
+        )
+        .unwrap_err();
+        assert_eq!(err.column, "PAYLOAD");
+        assert!(err.to_string().contains("column PAYLOAD"), "{err}");
+        assert!(
+            decode_cell(
+                &column(SnowflakeDataType::Object, None),
+                Some("[1]"),
+                Tz::UTC,
+                BinaryFormat::Hex
+            )
+            .is_err()
+        );
+    }
+
+    #[test]
+    fn decodes_epoch_dates_and_times() {
+        assert_eq!(
+            decode(SnowflakeDataType::Date, "19358"),
+            SnowflakeValue::Date(NaiveDate::from_ymd_opt(2023, 1, 1).unwrap())
+        );
+        assert_eq!(
+            decode(SnowflakeDataType::Date, "-1"),
+            SnowflakeValue::Date(NaiveDate::from_ymd_opt(1969, 12, 31).unwrap())
+        );
+        assert_eq!(
+            decode(SnowflakeDataType::Time, "49322.123456789"),
+            SnowflakeValue::Time(NaiveTime::from_hms_nano_opt(13, 42, 2, 123_456_789).unwrap())
+        );
+    }
+
+    #[test]
+    fn decodes_epoch_timestamps() {
+        let expected = DateTime::from_timestamp(1_625_097_600, 123_456_789).unwrap();
+        assert_eq!(
+            decode(SnowflakeDataType::TimestampNtz, "1625097600.123456789"),
+            SnowflakeValue::TimestampNtz(expected.naive_utc())
+        );
+        assert_eq!(
+            decode(SnowflakeDataType::TimestampLtz, "1625097600.123456789"),
+            SnowflakeValue::TimestampLtz(expected)
+        );
+
+        let SnowflakeValue::TimestampTz(tz) =
+            decode(SnowflakeDataType::TimestampTz, "1625097600.123456789 1560")
+        else {
+            panic!("expected a TIMESTAMP_TZ");
+        };
+        assert_eq!(tz, expected);
+        assert_eq!(tz.offset().local_minus_utc(), 2 * 60 * 60);
+    }
+
+    #[test]
+    fn decodes_pre_epoch_and_sub_second_timestamps() {
+        let half_second_before = DateTime::from_timestamp(-1, 500_000_000).unwrap();
+        for raw in [
+            "-0.5",
+            "-0.500000000",
+            "[-1, 500000000]",
+            r#"["-1", "500000000", "1440"]"#,
+        ] {
+            assert_eq!(
+                decode(SnowflakeDataType::TimestampLtz, raw),
+                SnowflakeValue::TimestampLtz(half_second_before),
+                "{raw}"
+            );
+        }
+        assert_eq!(
+            decode(SnowflakeDataType::TimestampNtz, "-86400.000000001"),
+            SnowflakeValue::TimestampNtz(
+                DateTime::from_timestamp(-86_401, 999_999_999)
+                    .unwrap()
+                    .naive_utc()
+            )
+        );
+        assert_eq!(
+            decode(SnowflakeDataType::TimestampNtz, "0.000000001"),
+            SnowflakeValue::TimestampNtz(DateTime::from_timestamp(0, 1).unwrap().naive_utc())
+        );
+
+        let SnowflakeValue::TimestampTz(tz) = decode(
+            SnowflakeDataType::TimestampTz,
+            "[1625097600, 123456789, 1080]",
+        ) else {
+            panic!("expected a TIMESTAMP_TZ");
+        };
+        assert_eq!(
+            tz,
+            DateTime::from_timestamp(1_625_097_600, 123_456_789).unwrap()
+        );
+        assert_eq!(tz.offset().local_minus_utc(), -6 * 60 * 60);
+        let SnowflakeValue::TimestampTz(tz) = decode(SnowflakeDataType::TimestampTz, "[0, 0]")
+        else {
+            panic!("expected a TIMESTAMP_TZ");
+        };
+        assert_eq!(tz.offset().local_minus_utc(), 0);
+
+        for raw in ["[1]", "[1, 1000000000]", "[a, 0]", "1.2.3"] {
+            assert!(
+                decode_cell(
+                    &column(SnowflakeDataType::TimestampLtz, None),
+                    Some(raw),
+                    Tz::UTC,
+                    BinaryFormat::Hex
+                )
+                .is_err(),
+                "{raw}"
+            );
+        }
+    }
+
+    #[test]
+    fn rejects_epochs_too_large_for_nanoseconds() {
+        // parses as an i128 of seconds, but not once scaled to nanoseconds
+        let seconds = "100000000000000000000000000000000000000";
+        assert!(
+            parse_epoch_nanos(seconds)
+                .unwrap_err()
+                .contains("out of range")
+        );
+        for data_type in [
+            SnowflakeDataType::Time,
+            SnowflakeDataType::TimestampNtz,
+            SnowflakeDataType::TimestampLtz,
+            SnowflakeDataType::TimestampTz,
+        ] {
+            for raw in [seconds.to_string(), format!("-{seconds}.5")] {
+                assert!(
+                    decode_cell(
+                        &column(data_type, None),
+                        Some(&raw),
+                        Tz::UTC,
+                        BinaryFormat::Hex
+                    )
+                    .is_err(),
+                    "{data_type:?} {raw}"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn decodes_formatted_values() {
+        assert_eq!(
+            decode(SnowflakeDataType::Date, "2023/01/01"),
+            SnowflakeValue::Date(NaiveDate::from_ymd_opt(2023, 1, 1).unwrap())
+        );
+        assert_eq!(
+            decode(
+                SnowflakeDataType::TimestampLtz,
+                "2021-07-01T00:00:00.000000000Z"
+            ),
+            SnowflakeValue::TimestampLtz(DateTime::from_timestamp(1_625_097_600, 0).unwrap())
+        );
+    }
+
+    #[test]
+    fn decodes_ltz_in_session_timezone() {
+        let ltz = column(SnowflakeDataType::TimestampLtz, None);
+        let decode_in =
+            |raw, timezone| decode_cell(&ltz, Some(raw), timezone, BinaryFormat::Hex).unwrap();
+        let new_york = chrono_tz::America::New_York;
+
+        // 12:30 in New York is 16:30 UTC during DST
+        let expected = DateTime::from_timestamp(1_625_157_000, 0).unwrap();
+        assert_eq!(
+            decode_in("2021-07-01T12:30:00.000000000", new_york),
+            SnowflakeValue::TimestampLtz(expected)
+        );
+        // values carrying an offset, or sent as epoch seconds, are instants already
+        assert_eq!(
+            decode_in("2021-07-01T16:30:00.000000000Z", new_york),
+            SnowflakeValue::TimestampLtz(expected)
+        );
+        assert_eq!(
+            decode_in("1625157000", new_york),
+            SnowflakeValue::TimestampLtz(expected)
+        );
+        // skipped by the switch to DST
+        assert!(
+            decode_cell(
+                &ltz,
+                Some("2021-03-14T02:30:00.000000000"),
+                new_york,
+                BinaryFormat::Hex
+            )
+            .is_err()
+        );
+    }
+
+    #[test]
+    fn keeps_geo_text_as_sent() {
+        let point = r#"{"coordinates": [-122.35, 37.55], "type": "Point"}"#;
+        assert_eq!(
+            decode(SnowflakeDataType::Geography, point),
+            SnowflakeValue::Geo(point.to_string())
+        );
+        assert_eq!(
+            decode(SnowflakeDataType::Geometry, "POINT(-122.35 37.55)"),
+            SnowflakeValue::Geo("POINT(-122.35 37.55)".to_string())
+        );
+        let geography = column(SnowflakeDataType::Geography, None);
+        assert_eq!(
+            decode_cell(&geography, None, Tz::UTC, BinaryFormat::Hex).unwrap(),
+            SnowflakeValue::Null
+        );
+    }
+}
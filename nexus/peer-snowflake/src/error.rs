@@ -0,0 +1,409 @@
+use std::time::Duration;
+
+use reqwest::StatusCode;
+use serde::Deserialize;
+use uuid::Uuid;
+
+use crate::{auth::SnowflakeAuthError, decode::SnowflakeDecodeError, retry::RetryPolicy};
+
+// warehouse suspended or still resuming, without leading zeros
+const WAREHOUSE_RESUMING: &str = "606";
+// characters of a non-Snowflake error page kept in `ServiceUnavailable`
+const BODY_SNIPPET_LEN: usize = 256;
+
+/// How a failed request to Snowflake should be handled.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ErrorClass {
+    /// Likely to succeed if sent again, e.g. after a rate limit or while a warehouse resumes.
+    Retryable,
+    /// Snowflake rejected the credentials, retrying won't help until they change.
+    AuthFailure,
+    /// The statement itself is at fault, and the error should be shown to the user.
+    UserError,
+    Fatal,
+}
+
+/// Snowflake error codes with a known [`ErrorClass`], without their leading zeros.
+const ERROR_CLASSES: &[(&str, ErrorClass)] = &[
+    // too many requests
+    ("429", ErrorClass::Retryable),
+    // SQL execution internal error
+    ("603", ErrorClass::Retryable),
+    // warehouse suspended or still resuming
+    ("606", ErrorClass::Retryable),
+    // authentication token expired
+    ("390114", ErrorClass::AuthFailure),
+    // JWT token invalid
+    ("390144", ErrorClass::AuthFailure),
+    // incorrect username or password
+    ("390100", ErrorClass::AuthFailure),
+    // role not granted to the user
+    ("390186", ErrorClass::AuthFailure),
+    // IP address blocked by a network policy
+    ("390422", ErrorClass::AuthFailure),
+    // statement reached its statement or warehouse timeout
+    ("630", ErrorClass::UserError),
+    // invalid identifier
+    ("904", ErrorClass::UserError),
+    // SQL syntax error
+    ("1003", ErrorClass::UserError),
+    // object does not exist or not authorized
+    ("2003", ErrorClass::UserError),
+    // database does not exist or not authorized
+    ("2043", ErrorClass::UserError),
+    // numeric value not recognized
+    ("100038", ErrorClass::UserError),
+];
+
+/// Classifies a Snowflake error code such as `000606` (or `606`). Unknown codes are
+/// [`ErrorClass::Fatal`].
+pub fn classify_snowflake_error(code: &str) -> ErrorClass {
+    let code = code.trim().trim_start_matches('0');
+    ERROR_CLASSES
+        .iter()
+        .find(|(known, _)| *known == code)
+        .map_or(ErrorClass::Fatal, |&(_, class)| class)
+}
+
+/// The JSON body Snowflake sends with a failed SQL API request.
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, thiserror::Error)]
+#[serde(rename_all = "camelCase")]
+#[error("{message} (code {code})")]
+pub struct SnowflakeApiError {
+    pub code: String,
+    pub message: String,
+    /// SQLSTATE of a statement that failed, absent for errors outside of SQL such as auth.
+    #[serde(default)]
+    pub sql_state: Option<String>,
+    #[serde(default)]
+    pub statement_handle: Option<String>,
+    /// The id of the failed statement in Snowflake's UI and `QUERY_HISTORY`, when it differs
+    /// from `statement_handle`.
+    #[serde(default)]
+    pub query_id: Option<String>,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum SnowflakeError {
+    /// An error running a statement, tagged with the `requestId` it was submitted with.
+    #[error("{source} (requestId {request_id})")]
+    Statement {
+        request_id: Uuid,
+        #[source]
+        source: Box<SnowflakeError>,
+    },
+    #[error(transparent)]
+    Auth(#[from] SnowflakeAuthError),
+    #[error("request to Snowflake failed: {0}")]
+    Http(#[source] reqwest::Error),
+    /// No response within the connect or read timeout, see
+    /// [`HttpTimeouts`](crate::HttpTimeouts).
+    #[error("request to Snowflake timed out: {0}")]
+    RequestTimeout(#[source] reqwest::Error),
+    /// A failed request, with the error Snowflake described in the body.
+    #[error("Snowflake returned {status}: {error}")]
+    Api {
+        status: StatusCode,
+        #[source]
+        error: SnowflakeApiError,
+    },
+    /// A failed request whose body isn't a Snowflake error, e.g. a proxy's HTML error page.
+    #[error("unexpected response from Snowflake: {status}\n{body}")]
+    UnexpectedResponse { status: StatusCode, body: String },
+    /// A 5xx response whose body isn't a Snowflake error, such as the HTML page its gateway
+    /// serves during maintenance, with the start of that body.
+    #[error("Snowflake is unavailable ({status}): {snippet}")]
+    ServiceUnavailable { status: StatusCode, snippet: String },
+    #[error("failed to parse Snowflake response {body}: {source}")]
+    InvalidResponse {
+        body: String,
+        #[source]
+        source: serde_json::Error,
+    },
+    #[error("failed to encode request body: {0}")]
+    RequestEncoding(#[from] std::io::Error),
+    #[error("statement has {placeholders} placeholders but {binds} values were bound")]
+    BindCountMismatch { placeholders: usize, binds: usize },
+    #[error("a batch needs at least one statement")]
+    EmptyBatch,
+    /// Snowflake answered a batch with a different number of results than it has statements.
+    #[error("batch of {statements} statements returned {results} results")]
+    BatchMismatch { statements: usize, results: usize },
+    #[error("invalid {kind} identifier {name:?}")]
+    InvalidIdentifier { kind: &'static str, name: String },
+    #[error("Snowflake peer validation failed ({code}): {hint}\n{message}")]
+    ValidationFailed {
+        code: String,
+        hint: &'static str,
+        message: String,
+    },
+    #[error(transparent)]
+    Decode(#[from] SnowflakeDecodeError),
+    #[cfg(feature = "arrow")]
+    #[error("failed to decode Arrow result: {0}")]
+    Arrow(#[from] arrow::error::ArrowError),
+    #[cfg(feature = "arrow")]
+    #[error("invalid Arrow rowset: {0}")]
+    InvalidArrow(String),
+    #[error("invalid Snowflake base URL {url}: {reason}")]
+    InvalidBaseUrl { url: String, reason: String },
+    #[error("the Snowflake client pool is shutting down")]
+    ShuttingDown,
+    /// The result is past a cap of the client's [`ResultLimits`](crate::ResultLimits).
+    #[error("result exceeds {limit} of {max}, stopped fetching it")]
+    ResultTooLarge { limit: &'static str, max: u64 },
+    #[error("timed out waiting for Snowflake statement {statement_handle}")]
+    Timeout { statement_handle: String },
+    /// The whole call, retries included, took longer than [`HttpTimeouts::total`].
+    ///
+    /// [`HttpTimeouts::total`]: crate::HttpTimeouts::total
+    #[error("Snowflake operation did not complete within {timeout:?}")]
+    OperationTimeout { timeout: Duration },
+    /// The client's circuit breaker is open, see
+    /// [`SnowflakeClient::with_circuit_breaker`](crate::SnowflakeClient::with_circuit_breaker).
+    #[error(
+        "Snowflake peer keeps failing ({class:?}), not sending statements for another {retry_in:?}"
+    )]
+    CircuitOpen {
+        class: ErrorClass,
+        retry_in: Duration,
+    },
+    /// The caller's cancellation token fired, see
+    /// [`SnowflakeClient::execute_cancellable`](crate::SnowflakeClient::execute_cancellable).
+    #[error("the statement was cancelled")]
+    Cancelled,
+    /// A `PUT` that Snowflake or this client can't carry out, e.g. to a stage on local storage.
+    #[error("file transfer to the stage failed: {0}")]
+    FileTransfer(String),
+    /// The stage's cloud storage rejected an upload.
+    #[error("upload to {location_type} stage storage failed with {status}: {body}")]
+    StageUpload {
+        location_type: String,
+        status: StatusCode,
+        body: String,
+    },
+}
+
+impl From<reqwest::Error> for SnowflakeError {
+    fn from(err: reqwest::Error) -> Self {
+        if err.is_timeout() {
+            SnowflakeError::RequestTimeout(err)
+        } else {
+            SnowflakeError::Http(err)
+        }
+    }
+}
+
+impl SnowflakeError {
+    /// The error for a response with a non-success `status`, structured when `body` is a
+    /// Snowflake error and kept as text otherwise, truncated for server errors.
+    pub(crate) fn from_response(status: StatusCode, body: String) -> Self {
+        match serde_json::from_str(&body) {
+            Ok(error) => SnowflakeError::Api { status, error },
+            Err(_) if status.is_server_error() => SnowflakeError::ServiceUnavailable {
+                status,
+                snippet: body_snippet(&body),
+            },
+            Err(_) => SnowflakeError::UnexpectedResponse { status, body },
+        }
+    }
+
+    /// The error Snowflake returned, if that's what this is.
+    pub fn api_error(&self) -> Option<&SnowflakeApiError> {
+        match self.inner() {
+            SnowflakeError::Api { error, .. } => Some(error),
+            _ => None,
+        }
+    }
+
+    /// The error itself, without the statement context.
+    pub fn inner(&self) -> &SnowflakeError {
+        match self {
+            SnowflakeError::Statement { source, .. } => source.inner(),
+            _ => self,
+        }
+    }
+
+    /// The `requestId` of the statement that failed, if the error came from running one.
+    pub fn request_id(&self) -> Option<Uuid> {
+        match self {
+            SnowflakeError::Statement { request_id, .. } => Some(*request_id),
+            _ => None,
+        }
+    }
+
+    /// How this error should be handled, from the Snowflake error code when there is one.
+    pub fn class(&self) -> ErrorClass {
+        match self.inner() {
+            SnowflakeError::Auth(_) => ErrorClass::AuthFailure,
+            SnowflakeError::Http(err) if err.is_connect() => ErrorClass::Retryable,
+            SnowflakeError::RequestTimeout(_) => ErrorClass::Retryable,
+            SnowflakeError::Api { status, error } => match classify_snowflake_error(&error.code) {
+                ErrorClass::Fatal => status_class(*status),
+                class => class,
+            },
+            SnowflakeError::UnexpectedResponse { status, .. }
+            | SnowflakeError::StageUpload { status, .. } => status_class(*status),
+            SnowflakeError::ServiceUnavailable { .. } => ErrorClass::Retryable,
+            SnowflakeError::ValidationFailed { code, .. } => classify_snowflake_error(code),
+            SnowflakeError::CircuitOpen { class, .. } => *class,
+            SnowflakeError::BindCountMismatch { .. }
+            | SnowflakeError::EmptyBatch
+            | SnowflakeError::InvalidIdentifier { .. }
+            | SnowflakeError::Decode(_)
+            | SnowflakeError::Timeout { .. }
+            | SnowflakeError::OperationTimeout { .. }
+            | SnowflakeError::ResultTooLarge { .. }
+            | SnowflakeError::Cancelled => ErrorClass::UserError,
+            _ => ErrorClass::Fatal,
+        }
+    }
+
+    /// Whether Snowflake rejected the statement because its warehouse is suspended or still
+    /// resuming (code `000606`).
+    pub fn is_warehouse_resuming(&self) -> bool {
+        self.api_error()
+            .is_some_and(|error| error.code.trim().trim_start_matches('0') == WAREHOUSE_RESUMING)
+    }
+
+    /// Whether the same request may succeed if sent again.
+    pub fn is_transient(&self) -> bool {
+        self.class() == ErrorClass::Retryable
+    }
+}
+
+fn body_snippet(body: &str) -> String {
+    let body = body.trim();
+    match body.char_indices().nth(BODY_SNIPPET_LEN) {
+        Some((end, _)) => format!("{}...", &body[..end]),
+        None => body.to_string(),
+    }
+}
+
+fn status_class(status: StatusCode) -> ErrorClass {
+    if RetryPolicy::is_retryable_status(status) {
+        ErrorClass::Retryable
+    } else if status == StatusCode::UNAUTHORIZED || status == StatusCode::FORBIDDEN {
+        ErrorClass::AuthFailure
+    } else if status.is_client_error() {
+        ErrorClass::UserError
+    } else {
+        ErrorClass::Fatal
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn unexpected_response(status: StatusCode, body: &str) -> SnowflakeError {
+        SnowflakeError::from_response(status, body.to_string())
+    }
+
+    #[test]
+    fn classifies_error_codes() {
+        let cases = [
+            ("000606", ErrorClass::Retryable),
+            ("606", ErrorClass::Retryable),
+            ("429", ErrorClass::Retryable),
+            ("390114", ErrorClass::AuthFailure),
+            ("000630", ErrorClass::UserError),
+            ("002003", ErrorClass::UserError),
+            ("999999", ErrorClass::Fatal),
+        ];
+        for (code, expected) in cases {
+            assert_eq!(classify_snowflake_error(code), expected, "{code}");
+        }
+    }
+
+    #[test]
+    fn recognizes_warehouses_that_are_resuming() {
+        let resuming = r#"{"code": "000606", "message": "Warehouse 'COMPUTE_WH' is resuming."}"#;
+        let err = unexpected_response(StatusCode::UNPROCESSABLE_ENTITY, resuming);
+        assert!(err.is_warehouse_resuming());
+        assert!(err.is_transient());
+        let wrapped = SnowflakeError::Statement {
+            request_id: Uuid::nil(),
+            source: Box::new(err),
+        };
+        assert!(wrapped.is_warehouse_resuming());
+
+        let syntax_error = r#"{"code": "001003", "message": "SQL compilation error"}"#;
+        let err = unexpected_response(StatusCode::UNPROCESSABLE_ENTITY, syntax_error);
+        assert!(!err.is_warehouse_resuming());
+        assert!(!unexpected_response(StatusCode::BAD_GATEWAY, "606").is_warehouse_resuming());
+    }
+
+    #[test]
+    fn prefers_error_code_over_http_status() {
+        let syntax_error = r#"{"code": "001003", "message": "SQL compilation error"}"#;
+        let err = unexpected_response(StatusCode::INTERNAL_SERVER_ERROR, syntax_error);
+        assert_eq!(err.class(), ErrorClass::UserError);
+        assert!(!err.is_transient());
+
+        let unknown_code = r#"{"code": "999999", "message": "something else"}"#;
+        let err = unexpected_response(StatusCode::SERVICE_UNAVAILABLE, unknown_code);
+        assert!(err.is_transient());
+        let err = unexpected_response(StatusCode::UNAUTHORIZED, "<html></html>");
+        assert_eq!(err.class(), ErrorClass::AuthFailure);
+    }
+
+    #[test]
+    fn parses_snowflake_error_bodies() {
+        let body = r#"{
+            "code": "002003",
+            "message": "SQL compilation error:\nTable 'USERS' does not exist or not authorized.",
+            "sqlState": "42S02",
+            "statementHandle": "01b2c3d4-0000-1111-0000-000100020003",
+            "queryId": "01b2c3d4-0000-2222-0000-000100020003"
+        }"#;
+        let err = unexpected_response(StatusCode::UNPROCESSABLE_ENTITY, body);
+        assert_eq!(
+            err.api_error(),
+            Some(&SnowflakeApiError {
+                code: "002003".to_string(),
+                message: "SQL compilation error:\nTable 'USERS' does not exist or not authorized."
+                    .to_string(),
+                sql_state: Some("42S02".to_string()),
+                statement_handle: Some("01b2c3d4-0000-1111-0000-000100020003".to_string()),
+                query_id: Some("01b2c3d4-0000-2222-0000-000100020003".to_string()),
+            })
+        );
+        assert_eq!(err.class(), ErrorClass::UserError);
+    }
+
+    #[test]
+    fn keeps_non_json_bodies_as_text() {
+        let body = "<html><body><h1>404 Not Found</h1></body></html>";
+        let err = unexpected_response(StatusCode::NOT_FOUND, body);
+        assert!(err.api_error().is_none());
+        let SnowflakeError::UnexpectedResponse { status, body: text } = &err else {
+            panic!("expected the raw response, got {err:?}");
+        };
+        assert_eq!((*status, text.as_str()), (StatusCode::NOT_FOUND, body));
+        assert!(!err.is_transient());
+    }
+
+    #[test]
+    fn reports_html_server_errors_as_unavailable() {
+        let maintenance = format!(
+            "\n<!DOCTYPE html><html><title>Service Unavailable</title><body>{}</body></html>",
+            "Snowflake is undergoing scheduled maintenance. ".repeat(20)
+        );
+        let err = unexpected_response(StatusCode::SERVICE_UNAVAILABLE, &maintenance);
+        assert!(err.is_transient());
+        let SnowflakeError::ServiceUnavailable { status, snippet } = &err else {
+            panic!("expected the service to be unavailable, got {err:?}");
+        };
+        assert_eq!(*status, StatusCode::SERVICE_UNAVAILABLE);
+        assert!(snippet.starts_with("<!DOCTYPE html>"), "{snippet}");
+        assert_eq!(snippet.chars().count(), BODY_SNIPPET_LEN + 3);
+        assert!(err.to_string().contains("Service Unavailable"));
+
+        let err = unexpected_response(StatusCode::BAD_GATEWAY, "<html>bad gateway</html>");
+        let SnowflakeError::ServiceUnavailable { snippet, .. } = &err else {
+            panic!("expected the service to be unavailable, got {err:?}");
+        };
+        assert_eq!(snippet, "<html>bad gateway</html>");
+    }
+}
@@ -0,0 +1,155 @@
+//! Failing over between several key pairs of one Snowflake account, e.g. a primary service user
+//! and a break-glass one, so that a key rotated out of sync doesn't take the peer down.
+
+use std::{
+    sync::{
+        Arc,
+        atomic::{AtomicU32, AtomicUsize, Ordering},
+    },
+    time::Duration,
+};
+
+use secrecy::SecretString;
+use tracing::warn;
+
+use crate::{SnowflakeAuth, SnowflakeAuthError, SnowflakeCredential, credential};
+
+const DEFAULT_FAILURE_THRESHOLD: u32 = 2;
+
+/// Authenticates with the first of several [`SnowflakeAuth`]s until Snowflake rejects it
+/// repeatedly, then with the next one. The switch is sticky: the next one is used until it
+/// fails in turn, after which the one after it (or the first again) takes over. Each auth keeps
+/// its own JWT and refresh state.
+pub struct SnowflakeFailoverCredential {
+    auths: Vec<Arc<SnowflakeAuth>>,
+    active: AtomicUsize,
+    // consecutive auth failures of the active auth
+    failures: AtomicU32,
+    failure_threshold: u32,
+}
+
+impl SnowflakeFailoverCredential {
+    /// # Panics
+    ///
+    /// If `auths` is empty.
+    pub fn new(auths: Vec<Arc<SnowflakeAuth>>) -> Self {
+        assert!(!auths.is_empty(), "a failover needs at least one auth");
+        Self {
+            auths,
+            active: AtomicUsize::new(0),
+            failures: AtomicU32::new(0),
+            failure_threshold: DEFAULT_FAILURE_THRESHOLD,
+        }
+    }
+
+    /// Consecutive auth failures after which the next auth takes over, 2 by default.
+    pub fn with_failure_threshold(mut self, failure_threshold: u32) -> Self {
+        self.failure_threshold = failure_threshold.max(1);
+        self
+    }
+
+    /// The auth requests are currently authorized with.
+    pub fn active(&self) -> &Arc<SnowflakeAuth> {
+        &self.auths[self.active.load(Ordering::Relaxed)]
+    }
+}
+
+#[async_trait::async_trait]
+impl SnowflakeCredential for SnowflakeFailoverCredential {
+    fn token_type(&self) -> &'static str {
+        credential::KEYPAIR_JWT_TOKEN_TYPE
+    }
+
+    async fn authorization_header(&self) -> Result<SecretString, SnowflakeAuthError> {
+        self.active().authorization_header().await
+    }
+
+    fn cached_lifetime(&self) -> Option<Duration> {
+        self.active().cached_lifetime()
+    }
+
+    fn record_auth_failure(&self) {
+        if self.failures.fetch_add(1, Ordering::Relaxed) + 1 < self.failure_threshold {
+            return;
+        }
+        let from = self.active.load(Ordering::Relaxed);
+        let to = (from + 1) % self.auths.len();
+        // concurrent failures of the same auth switch only once
+        if from == to
+            || self
+                .active
+                .compare_exchange(from, to, Ordering::Relaxed, Ordering::Relaxed)
+                .is_err()
+        {
+            return;
+        }
+        self.failures.store(0, Ordering::Relaxed);
+        warn!(
+            "Snowflake keeps rejecting {}, failing over to {}",
+            self.auths[from].debug_summary(),
+            self.auths[to].debug_summary()
+        );
+    }
+
+    fn record_success(&self) {
+        self.failures.store(0, Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use pt::peerdb_peers::SnowflakeConfig;
+
+    use super::*;
+    use crate::{
+        RetryPolicy, SnowflakeClient,
+        mock::{MockSnowflakeServer, MockStatement, fast_polls, test_auth},
+    };
+
+    fn active_user(failover: &SnowflakeFailoverCredential) -> String {
+        failover.active().current_claims().unwrap().sub
+    }
+
+    #[test]
+    fn fails_over_after_repeated_failures_and_sticks() {
+        let failover =
+            SnowflakeFailoverCredential::new(vec![test_auth("primary"), test_auth("break_glass")]);
+        failover.record_auth_failure();
+        failover.record_success();
+        failover.record_auth_failure();
+        assert_eq!(active_user(&failover), "XY12345.PRIMARY");
+
+        failover.record_auth_failure();
+        assert_eq!(active_user(&failover), "XY12345.BREAK_GLASS");
+        failover.record_auth_failure();
+        failover.record_success();
+        assert_eq!(active_user(&failover), "XY12345.BREAK_GLASS");
+
+        // once the secondary fails too, the primary gets another turn
+        failover.record_auth_failure();
+        failover.record_auth_failure();
+        assert_eq!(active_user(&failover), "XY12345.PRIMARY");
+    }
+
+    #[tokio::test]
+    async fn client_reports_auth_failures_to_the_credential() {
+        let server = MockSnowflakeServer::start().await;
+        server.mount_statement(MockStatement::new("handle-1")).await;
+        server.fail_next("POST", "/api/v2/statements", 401, 2).await;
+        let failover = Arc::new(SnowflakeFailoverCredential::new(vec![
+            test_auth("primary"),
+            test_auth("break_glass"),
+        ]));
+        let client = SnowflakeClient::new(&SnowflakeConfig::default(), failover.clone())
+            .unwrap()
+            .with_endpoint_url(server.endpoint_url())
+            .with_poll_policy(fast_polls())
+            .with_retry_policy(RetryPolicy::never());
+
+        for _ in 0..2 {
+            client.execute("SELECT 1").await.unwrap_err();
+        }
+        client.execute("SELECT 1").await.unwrap();
+        assert_eq!(active_user(&failover), "XY12345.BREAK_GLASS");
+    }
+}
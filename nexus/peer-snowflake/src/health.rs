@@ -0,0 +1,208 @@
+//! Peer health for readiness probes, answered from what the client already knows unless a live
+//! check is asked for.
+
+use std::{
+    sync::{
+        Mutex, PoisonError,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use crate::{CircuitState, SnowflakeApiError, SnowflakeClient, SnowflakeError};
+
+/// The state of a peer's connection to Snowflake, see [`SnowflakeClient::health`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PeerHealth {
+    /// Whether the cached token has expired. A credential that hasn't fetched one yet counts as
+    /// valid, since it will on the next request.
+    pub credential_valid: bool,
+    /// How long the cached token has left, if the credential caches one.
+    pub credential_remaining: Option<Duration>,
+    /// When a statement last succeeded.
+    pub last_success: Option<SystemTime>,
+    /// When a statement last failed, and why.
+    pub last_error: Option<(SystemTime, String)>,
+    /// When Snowflake itself last rejected a statement, with the error it sent. Unlike
+    /// `last_error` it isn't replaced by failures that never reached Snowflake, e.g. timeouts.
+    pub last_api_error: Option<(SystemTime, SnowflakeApiError)>,
+    /// The client's circuit breaker, [`CircuitState::Closed`] if it has none.
+    pub circuit: CircuitState,
+}
+
+impl PeerHealth {
+    /// Whether the peer can be expected to run statements: its credential is valid, its circuit
+    /// isn't open, and it didn't fail more recently than it succeeded.
+    pub fn is_healthy(&self) -> bool {
+        self.credential_valid
+            && !matches!(self.circuit, CircuitState::Open { .. })
+            && match (&self.last_error, self.last_success) {
+                (Some((failed_at, _)), Some(succeeded_at)) => succeeded_at > *failed_at,
+                (Some(_), None) => false,
+                (None, _) => true,
+            }
+    }
+}
+
+/// Outcome of the last statements a client ran.
+#[derive(Debug, Default)]
+pub(crate) struct HealthTracker {
+    // unix millis, 0 until a statement succeeds
+    last_success_ms: AtomicU64,
+    last_error: Mutex<Option<(SystemTime, String)>>,
+    last_api_error: Mutex<Option<(SystemTime, SnowflakeApiError)>>,
+}
+
+impl HealthTracker {
+    pub(crate) fn record_success(&self) {
+        let now_ms = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_millis() as u64)
+            .unwrap_or_default();
+        self.last_success_ms.fetch_max(now_ms, Ordering::Relaxed);
+    }
+
+    pub(crate) fn record_failure(&self, err: &SnowflakeError) {
+        let failed_at = SystemTime::now();
+        if let Some(api_error) = err.api_error() {
+            *self
+                .last_api_error
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner) = Some((failed_at, api_error.clone()));
+        }
+        let mut last_error = self
+            .last_error
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        *last_error = Some((failed_at, err.to_string()));
+    }
+
+    /// Time since a statement last succeeded, `None` if none has.
+    pub(crate) fn idle_for(&self) -> Option<Duration> {
+        let last_success = self.last_success()?;
+        Some(last_success.elapsed().unwrap_or_default())
+    }
+
+    fn last_success(&self) -> Option<SystemTime> {
+        match self.last_success_ms.load(Ordering::Relaxed) {
+            0 => None,
+            ms => Some(UNIX_EPOCH + Duration::from_millis(ms)),
+        }
+    }
+
+    fn last_error(&self) -> Option<(SystemTime, String)> {
+        self.last_error
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clone()
+    }
+
+    fn last_api_error(&self) -> Option<(SystemTime, SnowflakeApiError)> {
+        self.last_api_error
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clone()
+    }
+}
+
+impl SnowflakeClient {
+    /// Reports whether the peer can currently authenticate, from the cached credential and the
+    /// statements run so far. Only with `live_check` does this also run
+    /// [`SnowflakeClient::validate`], whose outcome is then part of the report.
+    pub async fn health(&self, live_check: bool) -> PeerHealth {
+        if live_check {
+            // recorded by the tracker like any other statement
+            let _ = self.validate().await;
+        }
+        let credential_remaining = self.credential().cached_lifetime();
+        PeerHealth {
+            credential_valid: credential_remaining != Some(Duration::ZERO),
+            credential_remaining,
+            last_success: self.health_tracker().last_success(),
+            last_error: self.health_tracker().last_error(),
+            last_api_error: self.health_tracker().last_api_error(),
+            circuit: self.circuit_state(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::{MockSnowflakeServer, MockStatement};
+
+    #[tokio::test]
+    async fn reports_without_querying_unless_asked() {
+        let server = MockSnowflakeServer::start().await;
+        server
+            .mount_statement(MockStatement::new("handle-1").partition(vec![vec![None]]))
+            .await;
+        let client = server.client();
+
+        let health = client.health(false).await;
+        assert!(health.credential_valid);
+        assert!(health.credential_remaining.unwrap() > Duration::from_secs(3000));
+        assert!(health.last_success.is_none() && health.last_error.is_none());
+        assert!(health.is_healthy());
+        assert!(server.requests_to("/api/v2/statements").await.is_empty());
+
+        let health = client.health(true).await;
+        assert!(health.last_success.is_some());
+        assert!(health.is_healthy());
+        // the warm-up HEAD aside
+        let submitted = server.requests_to("/api/v2/statements").await;
+        assert_eq!(
+            submitted
+                .iter()
+                .filter(|request| request.method.as_str() == "POST")
+                .count(),
+            1
+        );
+    }
+
+    #[tokio::test]
+    async fn reports_the_last_failure() {
+        let server = MockSnowflakeServer::start().await;
+        server.fail_next("POST", "/api/v2/statements", 422, 1).await;
+        let client = server.client();
+
+        let health = client.health(true).await;
+        assert!(health.last_success.is_none());
+        assert!(health.last_error.unwrap().1.contains("422"));
+        assert!(!client.health(false).await.is_healthy());
+    }
+
+    #[tokio::test]
+    async fn keeps_the_last_error_snowflake_sent() {
+        let server = MockSnowflakeServer::start().await;
+        server
+            .mount_statement(MockStatement::new("handle-1").pending_polls(u64::MAX))
+            .await;
+        server
+            .fail_next_with(
+                "POST",
+                "/api/v2/statements",
+                422,
+                serde_json::json!({"code": "002003", "message": "Object 'T' does not exist."}),
+            )
+            .await;
+        let client = server.client().with_poll_policy(crate::PollPolicy {
+            max_wait: Some(Duration::from_millis(30)),
+            ..crate::mock::fast_polls()
+        });
+
+        client.execute("SELECT * FROM t").await.unwrap_err();
+        let err = client.execute("SELECT 1").await.unwrap_err();
+        assert!(
+            matches!(err.inner(), SnowflakeError::Timeout { .. }),
+            "{err:?}"
+        );
+
+        let health = client.health(false).await;
+        let (last_error_at, last_error) = health.last_error.unwrap();
+        assert!(last_error.contains("timed out"), "{last_error}");
+        let (api_error_at, api_error) = health.last_api_error.unwrap();
+        assert_eq!(api_error.code, "002003");
+        assert!(api_error_at <= last_error_at);
+    }
+}
@@ -0,0 +1,139 @@
+use std::{
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
+
+use crate::{ErrorClass, SnowflakeError};
+
+/// Counters of the statements run by a [`SnowflakeClient`](crate::SnowflakeClient), for export
+/// to whatever metrics backend the caller uses.
+#[derive(Debug, Default)]
+pub struct SnowflakeMetrics {
+    submitted: AtomicU64,
+    succeeded: AtomicU64,
+    failed_retryable: AtomicU64,
+    failed_auth: AtomicU64,
+    failed_user: AtomicU64,
+    failed_fatal: AtomicU64,
+    retried: AtomicU64,
+    bytes_scanned: AtomicU64,
+}
+
+/// The values of [`SnowflakeMetrics`] at one point in time. All counters only ever grow.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct SnowflakeMetricsSnapshot {
+    pub submitted: u64,
+    pub succeeded: u64,
+    /// Failed statements, by [`ErrorClass`].
+    pub failed_retryable: u64,
+    pub failed_auth: u64,
+    pub failed_user: u64,
+    pub failed_fatal: u64,
+    /// Requests sent again after a transient failure, and statements resubmitted.
+    pub retried: u64,
+    /// Bytes scanned by succeeded statements, as reported in their `stats`.
+    pub bytes_scanned: u64,
+}
+
+impl SnowflakeMetrics {
+    pub(crate) fn record_submitted(&self) {
+        self.submitted.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn record_success(&self, bytes_scanned: u64) {
+        self.succeeded.fetch_add(1, Ordering::Relaxed);
+        self.bytes_scanned
+            .fetch_add(bytes_scanned, Ordering::Relaxed);
+    }
+
+    pub(crate) fn record_failure(&self, err: &SnowflakeError) {
+        let counter = match err.class() {
+            ErrorClass::Retryable => &self.failed_retryable,
+            ErrorClass::AuthFailure => &self.failed_auth,
+            ErrorClass::UserError => &self.failed_user,
+            ErrorClass::Fatal => &self.failed_fatal,
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn record_retry(&self) {
+        self.retried.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn snapshot(&self) -> SnowflakeMetricsSnapshot {
+        let load = |counter: &AtomicU64| counter.load(Ordering::Relaxed);
+        SnowflakeMetricsSnapshot {
+            submitted: load(&self.submitted),
+            succeeded: load(&self.succeeded),
+            failed_retryable: load(&self.failed_retryable),
+            failed_auth: load(&self.failed_auth),
+            failed_user: load(&self.failed_user),
+            failed_fatal: load(&self.failed_fatal),
+            retried: load(&self.retried),
+            bytes_scanned: load(&self.bytes_scanned),
+        }
+    }
+}
+
+// upper bounds of the signing time buckets, around the millisecond an RSA-2048 signature takes
+const SIGNING_BUCKET_BOUNDS: [Duration; 6] = [
+    Duration::from_micros(100),
+    Duration::from_micros(500),
+    Duration::from_millis(1),
+    Duration::from_millis(5),
+    Duration::from_millis(10),
+    Duration::from_millis(50),
+];
+
+/// Time a [`SnowflakeAuth`](crate::SnowflakeAuth) spent signing JWTs.
+#[derive(Debug, Default)]
+pub(crate) struct SigningMetrics {
+    signed: AtomicU64,
+    total_nanos: AtomicU64,
+    max_nanos: AtomicU64,
+    // parallel to `SIGNING_BUCKET_BOUNDS`, plus one for the slower signatures
+    buckets: [AtomicU64; SIGNING_BUCKET_BOUNDS.len() + 1],
+}
+
+/// The values of a [`SnowflakeAuth`](crate::SnowflakeAuth)'s signing counters at one point in
+/// time, to tell whether signing JWTs weighs on the async executor. All of them only ever grow.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct SnowflakeAuthMetricsSnapshot {
+    pub jwts_signed: u64,
+    /// Time spent signing, over all JWTs.
+    pub signing_time: Duration,
+    pub max_signing_time: Duration,
+    /// JWTs by signing time: `signing_histogram[i]` counts those signed within
+    /// [`SIGNING_BUCKET_BOUNDS`](Self::SIGNING_BUCKET_BOUNDS)`[i]` but not the bound before,
+    /// and the last bucket those slower than every bound.
+    pub signing_histogram: [u64; SIGNING_BUCKET_BOUNDS.len() + 1],
+}
+
+impl SnowflakeAuthMetricsSnapshot {
+    pub const SIGNING_BUCKET_BOUNDS: [Duration; SIGNING_BUCKET_BOUNDS.len()] =
+        SIGNING_BUCKET_BOUNDS;
+}
+
+impl SigningMetrics {
+    pub(crate) fn record(&self, signing_duration: Duration) {
+        let nanos = u64::try_from(signing_duration.as_nanos()).unwrap_or(u64::MAX);
+        self.signed.fetch_add(1, Ordering::Relaxed);
+        self.total_nanos.fetch_add(nanos, Ordering::Relaxed);
+        self.max_nanos.fetch_max(nanos, Ordering::Relaxed);
+        let bucket = SIGNING_BUCKET_BOUNDS
+            .iter()
+            .position(|bound| signing_duration <= *bound)
+            .unwrap_or(SIGNING_BUCKET_BOUNDS.len());
+        self.buckets[bucket].fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn snapshot(&self) -> SnowflakeAuthMetricsSnapshot {
+        let load = |counter: &AtomicU64| counter.load(Ordering::Relaxed);
+        SnowflakeAuthMetricsSnapshot {
+            jwts_signed: load(&self.signed),
+            signing_time: Duration::from_nanos(load(&self.total_nanos)),
+            max_signing_time: Duration::from_nanos(load(&self.max_nanos)),
+            signing_histogram: self.buckets.each_ref().map(load),
+        }
+    }
+}
@@ -0,0 +1,666 @@
+//! An in-process stand-in for the SQL API, for tests of the client against canned responses.
+
+use std::{sync::Arc, time::Duration};
+
+use base64::prelude::{BASE64_URL_SAFE_NO_PAD, Engine as _};
+use pt::peerdb_peers::SnowflakeConfig;
+use serde_json::{Value, json};
+use wiremock::{
+    Match, Mock, MockServer, Request, Respond, ResponseTemplate,
+    matchers::{header, method, path, path_regex, query_param, query_param_is_missing},
+};
+
+use crate::{PollPolicy, SnowflakeAuth, SnowflakeClient, SnowflakeRow};
+
+const STATEMENTS_PATH: &str = "/api/v2/statements";
+const LOGIN_PATH: &str = "/session/v1/login-request";
+const FILE_TRANSFER_PATH: &str = "/queries/v1/query-request";
+// where the mock's GCS stage keeps its files
+const STAGE_STORAGE_PATH: &str = "/stage-storage";
+const SESSION_TOKEN: &str = "mock-session-token";
+
+/// A key-pair credential for the mock as `username`, signed with the test key in `tests/assets`.
+pub(crate) fn test_auth(username: &str) -> Arc<SnowflakeAuth> {
+    let auth = SnowflakeAuth::new(
+        "xy12345.us-east-1".to_string(),
+        username.to_string(),
+        include_str!("../tests/assets/rsa_key_pkcs8.pem"),
+        None,
+        3000,
+        3600,
+    )
+    .unwrap();
+    Arc::new(auth)
+}
+
+/// Polls that don't slow tests down.
+pub(crate) fn fast_polls() -> PollPolicy {
+    PollPolicy {
+        initial_interval: Duration::from_millis(5),
+        max_interval: Duration::from_millis(20),
+        max_wait: None,
+    }
+}
+
+/// Matches requests authorized with a key-pair JWT shaped like the ones `SnowflakeAuth` makes.
+struct KeyPairJwt;
+
+impl KeyPairJwt {
+    fn is_well_formed(jwt: &str) -> bool {
+        let decode = |part: &str| -> Option<Value> {
+            serde_json::from_slice(&BASE64_URL_SAFE_NO_PAD.decode(part).ok()?).ok()
+        };
+        let mut parts = jwt.split('.');
+        let (Some(header), Some(claims), Some(signature), None) =
+            (parts.next(), parts.next(), parts.next(), parts.next())
+        else {
+            return false;
+        };
+        let (Some(header), Some(claims)) = (decode(header), decode(claims)) else {
+            return false;
+        };
+        let (Some(iss), Some(sub)) = (claims["iss"].as_str(), claims["sub"].as_str()) else {
+            return false;
+        };
+        let (Some(iat), Some(exp)) = (claims["iat"].as_u64(), claims["exp"].as_u64()) else {
+            return false;
+        };
+        matches!(header["alg"].as_str(), Some("RS256" | "ES256"))
+            && !signature.is_empty()
+            && iss.starts_with(&format!("{sub}.SHA256:"))
+            && sub == sub.to_uppercase()
+            && iat < exp
+    }
+}
+
+impl Match for KeyPairJwt {
+    fn matches(&self, request: &Request) -> bool {
+        let header = |name: &str| request.headers.get(name).and_then(|v| v.to_str().ok());
+        header("X-Snowflake-Authorization-Token-Type") == Some("KEYPAIR_JWT")
+            && header("Authorization")
+                .and_then(|authorization| authorization.strip_prefix("Bearer "))
+                .is_some_and(KeyPairJwt::is_well_formed)
+    }
+}
+
+/// Answers the `PUT`s of a session with an upload of the file to the mock's stage storage.
+struct PutResponder {
+    storage_url: String,
+    encryption_material: Option<Value>,
+}
+
+impl Respond for PutResponder {
+    fn respond(&self, request: &Request) -> ResponseTemplate {
+        let body: Value = request.body_json().unwrap_or_default();
+        let sql = body["sqlText"].as_str().unwrap_or_default();
+        // PUT 'file://<name>' '@<stage>/<path>/' ...
+        let Some(file_name) = sql
+            .split_once("'file://")
+            .and_then(|(_, rest)| rest.split_once('\''))
+            .map(|(file_name, _)| file_name)
+        else {
+            return ResponseTemplate::new(200).set_body_json(json!({
+                "success": false,
+                "code": "001003",
+                "message": "SQL compilation error: syntax error",
+                "data": { "sqlState": "42000" },
+            }));
+        };
+        ResponseTemplate::new(200).set_body_json(json!({
+            "success": true,
+            "data": {
+                "command": "UPLOAD",
+                "stageInfo": {
+                    "locationType": "GCS",
+                    "location": "mock-stage-bucket/stages/1/",
+                    "presignedUrl": format!("{}/{file_name}", self.storage_url),
+                    "creds": {},
+                },
+                "encryptionMaterial": self.encryption_material,
+            },
+        }))
+    }
+}
+
+/// A statement the mock runs: its result, split into partitions, and how long it takes.
+pub(crate) struct MockStatement {
+    handle: String,
+    columns: Vec<Value>,
+    partitions: Vec<Vec<SnowflakeRow>>,
+    pending_polls: u64,
+    bytes_scanned: u64,
+    children: Vec<String>,
+    query_id: Option<String>,
+}
+
+impl MockStatement {
+    pub(crate) fn new(handle: &str) -> Self {
+        Self {
+            handle: handle.to_string(),
+            columns: Vec::new(),
+            partitions: Vec::new(),
+            pending_polls: 0,
+            bytes_scanned: 0,
+            children: Vec::new(),
+            query_id: None,
+        }
+    }
+
+    /// Adds a column, with `r#type` as spelled in `rowType` (e.g. `fixed`).
+    pub(crate) fn column(mut self, name: &str, r#type: &str) -> Self {
+        self.columns.push(json!({
+            "name": name,
+            "type": r#type,
+            "nullable": true,
+            "scale": 0,
+        }));
+        self
+    }
+
+    pub(crate) fn partition(mut self, rows: Vec<SnowflakeRow>) -> Self {
+        self.partitions.push(rows);
+        self
+    }
+
+    /// Answers this many polls with 202 before returning the result.
+    pub(crate) fn pending_polls(mut self, pending_polls: u64) -> Self {
+        self.pending_polls = pending_polls;
+        self
+    }
+
+    pub(crate) fn bytes_scanned(mut self, bytes_scanned: u64) -> Self {
+        self.bytes_scanned = bytes_scanned;
+        self
+    }
+
+    /// Reports `query_id` as the statement's `queryId`, which is left out otherwise.
+    pub(crate) fn query_id(mut self, query_id: &str) -> Self {
+        self.query_id = Some(query_id.to_string());
+        self
+    }
+
+    /// Makes this the parent of a multi-statement request, whose statements have `handles`.
+    /// Those are mounted separately.
+    pub(crate) fn children(mut self, handles: &[&str]) -> Self {
+        self.children = handles.iter().map(|handle| handle.to_string()).collect();
+        self
+    }
+
+    fn result_set(&self) -> Value {
+        let partition_info: Vec<_> = self
+            .partitions
+            .iter()
+            .map(|rows| {
+                json!({
+                    "rowCount": rows.len(),
+                    "uncompressedSize": json!(rows).to_string().len(),
+                })
+            })
+            .collect();
+        let mut result_set = json!({
+            "statementHandle": self.handle,
+            "data": self.partitions.first().cloned().unwrap_or_default(),
+            "resultSetMetaData": {
+                "partitionInfo": partition_info,
+                "rowType": self.columns,
+            },
+            "stats": { "bytesScanned": self.bytes_scanned },
+        });
+        if !self.children.is_empty() {
+            result_set["statementHandles"] = json!(self.children);
+        }
+        if let Some(query_id) = &self.query_id {
+            result_set["queryId"] = json!(query_id);
+        }
+        result_set
+    }
+}
+
+pub(crate) struct MockSnowflakeServer {
+    server: MockServer,
+}
+
+impl MockSnowflakeServer {
+    /// Starts a server that answers 401 to SQL API requests without a well-formed key-pair
+    /// JWT.
+    pub(crate) async fn start() -> Self {
+        let server = MockServer::start().await;
+        Mock::given(|request: &Request| {
+            request.url.path().starts_with(STATEMENTS_PATH) && !KeyPairJwt.matches(request)
+        })
+        .respond_with(ResponseTemplate::new(401).set_body_json(json!({
+            "code": "390144",
+            "message": "JWT token is invalid.",
+        })))
+        .with_priority(1)
+        .mount(&server)
+        .await;
+        Self { server }
+    }
+
+    /// The statements endpoint of this server.
+    pub(crate) fn endpoint_url(&self) -> String {
+        format!("{}{STATEMENTS_PATH}", self.server.uri())
+    }
+
+    /// A client for this server, authenticating with [`test_auth`] and polling every few
+    /// milliseconds.
+    pub(crate) fn client(&self) -> SnowflakeClient {
+        SnowflakeClient::new(&SnowflakeConfig::default(), test_auth("peerdb"))
+            .unwrap()
+            .with_endpoint_url(self.endpoint_url())
+            .with_poll_policy(fast_polls())
+    }
+
+    /// Accepts submissions with `statement`'s handle and answers its polls, partition fetches
+    /// and cancels. Submissions go to the first statement mounted.
+    pub(crate) async fn mount_statement(&self, statement: MockStatement) {
+        let handle_path = format!("{STATEMENTS_PATH}/{}", statement.handle);
+        let running = ResponseTemplate::new(202).set_body_json(json!({
+            "statementHandle": statement.handle,
+            "message": "Asynchronous execution in progress.",
+        }));
+
+        Mock::given(method("POST"))
+            .and(path(STATEMENTS_PATH))
+            .respond_with(running.clone())
+            .mount(&self.server)
+            .await;
+        if statement.pending_polls > 0 {
+            Mock::given(method("GET"))
+                .and(path(handle_path.as_str()))
+                .and(query_param_is_missing("partition"))
+                .respond_with(running)
+                .up_to_n_times(statement.pending_polls)
+                .with_priority(2)
+                .mount(&self.server)
+                .await;
+        }
+        Mock::given(method("GET"))
+            .and(path(handle_path.as_str()))
+            .and(query_param_is_missing("partition"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(statement.result_set()))
+            .mount(&self.server)
+            .await;
+        for (partition, rows) in statement.partitions.iter().enumerate().skip(1) {
+            Mock::given(method("GET"))
+                .and(path(handle_path.as_str()))
+                .and(query_param("partition", partition.to_string()))
+                .respond_with(ResponseTemplate::new(200).set_body_json(json!({ "data": rows })))
+                .mount(&self.server)
+                .await;
+        }
+        Mock::given(method("POST"))
+            .and(path(format!("{handle_path}/cancel")))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+                "statementHandle": statement.handle,
+                "message": "successfully canceled",
+            })))
+            .mount(&self.server)
+            .await;
+    }
+
+    /// Sets up an internal stage: the key-pair logins of the drivers' endpoints get a session,
+    /// whose `PUT`s are answered with uploads to a GCS stage on this server, see
+    /// [`MockSnowflakeServer::stage_uploads`]. With `encryption_material` (a
+    /// `encryptionMaterial`), files are to be encrypted for the stage.
+    pub(crate) async fn mount_stage(&self, encryption_material: Option<Value>) {
+        Mock::given(method("POST"))
+            .and(path(LOGIN_PATH))
+            .and(|request: &Request| {
+                let body: Value = request.body_json().unwrap_or_default();
+                let login = &body["data"];
+                login["AUTHENTICATOR"] == "SNOWFLAKE_JWT"
+                    && login["ACCOUNT_NAME"] == "XY12345"
+                    && login["LOGIN_NAME"] == "PEERDB"
+                    && login["TOKEN"]
+                        .as_str()
+                        .is_some_and(KeyPairJwt::is_well_formed)
+            })
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+                "success": true,
+                "data": { "token": SESSION_TOKEN, "masterToken": "mock-master-token" },
+            })))
+            .mount(&self.server)
+            .await;
+        Mock::given(method("POST"))
+            .and(path(LOGIN_PATH))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+                "success": false,
+                "code": "390144",
+                "message": "JWT token is invalid.",
+                "data": null,
+            })))
+            .with_priority(10)
+            .mount(&self.server)
+            .await;
+        Mock::given(method("POST"))
+            .and(path(FILE_TRANSFER_PATH))
+            .and(header(
+                "Authorization",
+                format!("Snowflake Token=\"{SESSION_TOKEN}\"").as_str(),
+            ))
+            .respond_with(PutResponder {
+                storage_url: format!("{}{STAGE_STORAGE_PATH}", self.server.uri()),
+                encryption_material,
+            })
+            .mount(&self.server)
+            .await;
+        Mock::given(method("PUT"))
+            .and(path_regex(format!("^{STAGE_STORAGE_PATH}/")))
+            .respond_with(ResponseTemplate::new(200))
+            .mount(&self.server)
+            .await;
+        Mock::given(method("POST"))
+            .and(path("/session"))
+            .and(query_param("delete", "true"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({ "success": true })))
+            .mount(&self.server)
+            .await;
+    }
+
+    /// The files uploaded to the stage of [`MockSnowflakeServer::mount_stage`], by name.
+    pub(crate) async fn stage_uploads(&self) -> Vec<(String, Request)> {
+        self.server
+            .received_requests()
+            .await
+            .unwrap_or_default()
+            .into_iter()
+            .filter(|request| request.method.as_str() == "PUT")
+            .filter_map(|request| {
+                let prefix = format!("{STAGE_STORAGE_PATH}/");
+                let file_name = request.url.path().strip_prefix(&prefix)?.to_string();
+                Some((file_name, request))
+            })
+            .collect()
+    }
+
+    /// Answers the next `times` requests to `request_path` with `status` before falling
+    /// through to the other mocks.
+    pub(crate) async fn fail_next(
+        &self,
+        http_method: &str,
+        request_path: &str,
+        status: u16,
+        times: u64,
+    ) {
+        Mock::given(method(http_method))
+            .and(path(request_path))
+            .respond_with(ResponseTemplate::new(status))
+            .up_to_n_times(times)
+            .with_priority(1)
+            .mount(&self.server)
+            .await;
+    }
+
+    /// Like [`MockSnowflakeServer::fail_next`], once, with `body` as the error Snowflake sent.
+    pub(crate) async fn fail_next_with(
+        &self,
+        http_method: &str,
+        request_path: &str,
+        status: u16,
+        body: Value,
+    ) {
+        Mock::given(method(http_method))
+            .and(path(request_path))
+            .respond_with(ResponseTemplate::new(status).set_body_json(body))
+            .up_to_n_times(1)
+            .with_priority(1)
+            .mount(&self.server)
+            .await;
+    }
+
+    /// Answers the next request to `request_path` only after `delay`.
+    pub(crate) async fn delay_next(&self, http_method: &str, request_path: &str, delay: Duration) {
+        Mock::given(method(http_method))
+            .and(path(request_path))
+            .respond_with(ResponseTemplate::new(200).set_delay(delay))
+            .up_to_n_times(1)
+            .with_priority(1)
+            .mount(&self.server)
+            .await;
+    }
+
+    /// Requests received so far whose path ends with `suffix`.
+    pub(crate) async fn requests_to(&self, suffix: &str) -> Vec<Request> {
+        self.server
+            .received_requests()
+            .await
+            .unwrap_or_default()
+            .into_iter()
+            .filter(|request| request.url.path().ends_with(suffix))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::TryStreamExt;
+
+    use super::*;
+    use crate::{ResultLimits, RetryPolicy, SnowflakeError, SnowflakeValue};
+
+    fn row(id: &str) -> SnowflakeRow {
+        vec![Some(id.to_string())]
+    }
+
+    #[tokio::test]
+    async fn runs_async_multi_partition_statement() {
+        let server = MockSnowflakeServer::start().await;
+        server
+            .mount_statement(
+                MockStatement::new("handle-1")
+                    .column("ID", "fixed")
+                    .partition(vec![row("1"), row("2")])
+                    .partition(vec![row("3")])
+                    .partition(vec![row("4")])
+                    .pending_polls(2),
+            )
+            .await;
+        let client = Arc::new(server.client());
+
+        let result_set = client.execute("SELECT id FROM t").await.unwrap();
+        assert_eq!(result_set.statement_handle(), "handle-1");
+        assert_eq!(result_set.partition_count(), 3);
+
+        let rows: Vec<_> = result_set.into_rows(client).try_collect().await.unwrap();
+        assert_eq!(rows, vec![row("1"), row("2"), row("3"), row("4")]);
+    }
+
+    #[tokio::test]
+    async fn backpressures_partition_fetches_through_a_channel() {
+        let server = MockSnowflakeServer::start().await;
+        server
+            .mount_statement(
+                MockStatement::new("handle-1")
+                    .column("ID", "fixed")
+                    .partition(vec![row("1"), row("2")])
+                    .partition(vec![row("3")])
+                    .partition(vec![row("4")]),
+            )
+            .await;
+        let client = Arc::new(server.client());
+        let partition_fetches = async || {
+            server
+                .requests_to("/handle-1")
+                .await
+                .iter()
+                .filter(|request| request.url.query().is_some())
+                .count()
+        };
+
+        let result_set = client.execute("SELECT id FROM t").await.unwrap();
+        let mut rows = result_set.into_decoded_channel(client, 1);
+        let first = rows.recv().await.unwrap().unwrap();
+        assert_eq!(first, [SnowflakeValue::Number { value: 1, scale: 0 }]);
+        // the task is stuck on the third row until the second is taken
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert_eq!(partition_fetches().await, 1);
+
+        let mut ids = vec![first];
+        while let Some(row) = rows.recv().await {
+            ids.push(row.unwrap());
+        }
+        let ids: Vec<_> = ids
+            .into_iter()
+            .map(|row| match row[..] {
+                [SnowflakeValue::Number { value, scale: 0 }] => value,
+                _ => panic!("unexpected row {row:?}"),
+            })
+            .collect();
+        assert_eq!(ids, [1, 2, 3, 4]);
+        assert_eq!(partition_fetches().await, 2);
+    }
+
+    #[tokio::test]
+    async fn stops_fetching_past_result_limits() {
+        let server = MockSnowflakeServer::start().await;
+        server
+            .mount_statement(
+                MockStatement::new("handle-1")
+                    .column("ID", "fixed")
+                    .partition(vec![row("1"), row("2")])
+                    .partition(vec![row("3"), row("4")])
+                    .partition(vec![row("5")]),
+            )
+            .await;
+        let client = Arc::new(server.client().with_result_limits(ResultLimits {
+            max_rows: Some(3),
+            ..Default::default()
+        }));
+
+        let result_set = client.execute("SELECT id FROM t").await.unwrap();
+        let mut rows = result_set.into_rows(client);
+        assert_eq!(rows.try_next().await.unwrap(), Some(row("1")));
+        assert_eq!(rows.try_next().await.unwrap(), Some(row("2")));
+        let err = rows.try_next().await.unwrap_err();
+        assert!(
+            matches!(
+                err,
+                SnowflakeError::ResultTooLarge {
+                    limit: "max_rows",
+                    max: 3
+                }
+            ),
+            "{err:?}"
+        );
+        assert!(rows.try_next().await.unwrap().is_none());
+        // only polls, no partition fetches
+        let requests = server.requests_to("/handle-1").await;
+        assert!(requests.iter().all(|request| request.url.query().is_none()));
+
+        // the first partition alone is too large
+        let client = Arc::new(server.client().with_result_limits(ResultLimits {
+            max_bytes: Some(4),
+            ..Default::default()
+        }));
+        let result_set = client.execute("SELECT id FROM t").await.unwrap();
+        let err = result_set.into_rows(client).try_next().await.unwrap_err();
+        assert!(
+            matches!(
+                err,
+                SnowflakeError::ResultTooLarge {
+                    limit: "max_bytes",
+                    ..
+                }
+            ),
+            "{err:?}"
+        );
+    }
+
+    #[tokio::test]
+    async fn retries_rejected_submissions() {
+        let server = MockSnowflakeServer::start().await;
+        server
+            .mount_statement(MockStatement::new("handle-1").partition(vec![row("1")]))
+            .await;
+        server.fail_next("POST", STATEMENTS_PATH, 503, 2).await;
+        let client = server.client().with_retry_policy(RetryPolicy {
+            base_delay: Duration::from_millis(1),
+            ..Default::default()
+        });
+
+        let result_set = client.execute("SELECT 1").await.unwrap();
+        assert_eq!(result_set.rows(), &[row("1")]);
+        assert_eq!(server.requests_to(STATEMENTS_PATH).await.len(), 3);
+        assert_eq!(client.metrics().retried, 2);
+    }
+
+    #[tokio::test]
+    async fn retries_rejected_polls() {
+        let server = MockSnowflakeServer::start().await;
+        server
+            .mount_statement(MockStatement::new("handle-1").partition(vec![row("1")]))
+            .await;
+        server
+            .fail_next("GET", &format!("{STATEMENTS_PATH}/handle-1"), 429, 2)
+            .await;
+        let client = server.client().with_retry_policy(RetryPolicy {
+            base_delay: Duration::from_millis(1),
+            ..Default::default()
+        });
+
+        let result_set = client.execute("SELECT 1").await.unwrap();
+        assert_eq!(result_set.rows(), &[row("1")]);
+        assert_eq!(server.requests_to("/handle-1").await.len(), 3);
+        assert_eq!(client.metrics().retried, 2);
+    }
+
+    #[tokio::test]
+    async fn shares_one_retry_budget_across_a_statement() {
+        let server = MockSnowflakeServer::start().await;
+        server
+            .mount_statement(
+                MockStatement::new("handle-1")
+                    .column("ID", "fixed")
+                    .partition(vec![row("1")])
+                    .partition(vec![row("2")])
+                    .partition(vec![row("3")]),
+            )
+            .await;
+        server.fail_next("POST", STATEMENTS_PATH, 503, 1).await;
+        let client = Arc::new(
+            server
+                .client()
+                .with_retry_policy(RetryPolicy {
+                    base_delay: Duration::from_millis(1),
+                    ..Default::default()
+                })
+                .with_max_total_retries(2),
+        );
+
+        let result_set = client.execute("SELECT id FROM t").await.unwrap();
+        let mut rows = result_set.into_rows(client.clone());
+        assert_eq!(rows.try_next().await.unwrap(), Some(row("1")));
+        // the last retry left goes to the second partition, none to the third
+        server
+            .fail_next("GET", &format!("{STATEMENTS_PATH}/handle-1"), 503, 1)
+            .await;
+        assert_eq!(rows.try_next().await.unwrap(), Some(row("2")));
+        server
+            .fail_next("GET", &format!("{STATEMENTS_PATH}/handle-1"), 503, 1)
+            .await;
+        let err = rows.try_next().await.unwrap_err();
+        assert!(
+            matches!(err, SnowflakeError::ServiceUnavailable { .. }),
+            "{err:?}"
+        );
+        assert_eq!(client.metrics().retried, 2);
+
+        // every statement starts with a budget of its own
+        client.execute("SELECT 1").await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn rejects_requests_without_key_pair_jwt() {
+        let server = MockSnowflakeServer::start().await;
+        server.mount_statement(MockStatement::new("handle-1")).await;
+        let response = reqwest::Client::new()
+            .post(server.endpoint_url())
+            .bearer_auth("a.b.c")
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), 401);
+    }
+}
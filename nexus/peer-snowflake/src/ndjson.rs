@@ -0,0 +1,221 @@
+//! Result sets as JSON lines, one object per row keyed by column name, for tools that read
+//! results without going through the Postgres wire protocol.
+
+use std::io::{self, Write};
+
+use chrono::SecondsFormat;
+
+use crate::{SnowflakeDecodeError, SnowflakeResultSet, SnowflakeValue, bind::format_fixed};
+
+// the largest integer up to which every integer is exactly a double, 2^53
+const MAX_SAFE_INTEGER: u128 = 1 << 53;
+
+/// How [`SnowflakeResultSet::write_ndjson_with`] writes `NUMBER`s, which JSON parsers usually
+/// read into doubles.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum NdjsonNumbers {
+    /// Integers a double holds exactly, up to 2^53, as JSON numbers, everything else as strings
+    /// with every digit, e.g. `"12.50"`.
+    #[default]
+    Exact,
+    /// Always JSON numbers with every digit, e.g. `12.50`, for consumers that parse them as
+    /// decimals.
+    Number,
+    /// Always strings.
+    String,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum NdjsonError {
+    #[error(transparent)]
+    Decode(#[from] SnowflakeDecodeError),
+    #[error("failed to write JSON lines: {0}")]
+    Io(#[from] io::Error),
+}
+
+impl SnowflakeResultSet {
+    /// Writes the rows of this result set to `writer` as JSON lines, with [`NdjsonNumbers::Exact`].
+    /// These are the rows of the first partition only, see [`SnowflakeResultSet::rows`].
+    ///
+    /// NULLs are `null`, binary values hex strings, and dates, times and timestamps ISO 8601
+    /// strings, with an offset unless they are `TIMESTAMP_NTZ`.
+    pub fn write_ndjson<W: Write>(&self, writer: W) -> Result<(), NdjsonError> {
+        self.write_ndjson_with(writer, NdjsonNumbers::default())
+    }
+
+    /// Like [`SnowflakeResultSet::write_ndjson`], writing `NUMBER`s as `numbers` says.
+    pub fn write_ndjson_with<W: Write>(
+        &self,
+        mut writer: W,
+        numbers: NdjsonNumbers,
+    ) -> Result<(), NdjsonError> {
+        let names: Vec<&str> = self.column_names().collect();
+        for row in self.rows() {
+            let values = self.decode_row(row)?;
+            write_object(&mut writer, &names, &values, numbers)?;
+        }
+        Ok(writer.flush()?)
+    }
+}
+
+fn write_object(
+    writer: &mut impl Write,
+    names: &[&str],
+    values: &[SnowflakeValue],
+    numbers: NdjsonNumbers,
+) -> io::Result<()> {
+    writer.write_all(b"{")?;
+    for (index, (name, value)) in names.iter().zip(values).enumerate() {
+        if index > 0 {
+            writer.write_all(b",")?;
+        }
+        serde_json::to_writer(&mut *writer, name)?;
+        writer.write_all(b":")?;
+        write_value(writer, value, numbers)?;
+    }
+    writer.write_all(b"}\n")
+}
+
+fn write_value(
+    writer: &mut impl Write,
+    value: &SnowflakeValue,
+    numbers: NdjsonNumbers,
+) -> io::Result<()> {
+    let text = match value {
+        SnowflakeValue::Null => return writer.write_all(b"null"),
+        SnowflakeValue::Number { value, scale } => {
+            let decimal = format_fixed(*value, *scale);
+            let as_number = match numbers {
+                NdjsonNumbers::Exact => *scale == 0 && value.unsigned_abs() <= MAX_SAFE_INTEGER,
+                NdjsonNumbers::Number => true,
+                NdjsonNumbers::String => false,
+            };
+            if as_number {
+                return writer.write_all(decimal.as_bytes());
+            }
+            decimal
+        }
+        SnowflakeValue::Real(value) if value.is_finite() => {
+            return Ok(serde_json::to_writer(writer, value)?);
+        }
+        // JSON has no NaN or infinities
+        SnowflakeValue::Real(value) => format_float(*value),
+        SnowflakeValue::Text(text) | SnowflakeValue::Geo(text) => {
+            return Ok(serde_json::to_writer(writer, text)?);
+        }
+        SnowflakeValue::Boolean(value) => return Ok(serde_json::to_writer(writer, value)?),
+        SnowflakeValue::Date(date) => date.format("%Y-%m-%d").to_string(),
+        SnowflakeValue::Time(time) => time.format("%H:%M:%S%.f").to_string(),
+        SnowflakeValue::TimestampNtz(timestamp) => {
+            timestamp.format("%Y-%m-%dT%H:%M:%S%.f").to_string()
+        }
+        SnowflakeValue::TimestampLtz(timestamp) => {
+            timestamp.to_rfc3339_opts(SecondsFormat::AutoSi, true)
+        }
+        SnowflakeValue::TimestampTz(timestamp) => {
+            timestamp.to_rfc3339_opts(SecondsFormat::AutoSi, false)
+        }
+        SnowflakeValue::Binary(bytes) => hex::encode(bytes),
+        SnowflakeValue::Variant(json) => return Ok(serde_json::to_writer(writer, json)?),
+    };
+    Ok(serde_json::to_writer(writer, &text)?)
+}
+
+fn format_float(value: f64) -> String {
+    if value.is_nan() {
+        "NaN".to_string()
+    } else if value.is_infinite() {
+        if value > 0.0 { "Infinity" } else { "-Infinity" }.to_string()
+    } else {
+        value.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn result_set(columns: &[(&str, &str, u32)], data: serde_json::Value) -> SnowflakeResultSet {
+        let row_type: Vec<_> = columns
+            .iter()
+            .map(|(name, r#type, scale)| json!({"name": name, "type": r#type, "scale": scale}))
+            .collect();
+        serde_json::from_value(json!({
+            "statementHandle": "handle-1",
+            "data": data,
+            "resultSetMetaData": {
+                "partitionInfo": [{"rowCount": 2, "uncompressedSize": 0}],
+                "rowType": row_type,
+            },
+        }))
+        .unwrap()
+    }
+
+    fn ndjson(result_set: &SnowflakeResultSet, numbers: NdjsonNumbers) -> String {
+        let mut out = Vec::new();
+        result_set.write_ndjson_with(&mut out, numbers).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn writes_a_json_object_per_row() {
+        let result_set = result_set(
+            &[
+                ("ID", "fixed", 0),
+                ("NAME", "text", 0),
+                ("ACTIVE", "boolean", 0),
+                ("SCORE", "real", 0),
+            ],
+            json!([
+                ["1", "ada \"the first\"", "true", "1.5"],
+                ["2", null, "false", "NaN"]
+            ]),
+        );
+        let mut out = Vec::new();
+        result_set.write_ndjson(&mut out).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            concat!(
+                r#"{"ID":1,"NAME":"ada \"the first\"","ACTIVE":true,"SCORE":1.5}"#,
+                "\n",
+                r#"{"ID":2,"NAME":null,"ACTIVE":false,"SCORE":"NaN"}"#,
+                "\n",
+            )
+        );
+    }
+
+    #[test]
+    fn keeps_every_digit_of_numbers() {
+        let result_set = result_set(
+            &[("PRICE", "fixed", 2), ("BIG", "fixed", 0)],
+            json!([["12.50", "123456789012345678901234567890"], ["-0.05", "42"]]),
+        );
+        let lines = |numbers| -> Vec<serde_json::Value> {
+            ndjson(&result_set, numbers)
+                .lines()
+                .map(|line| serde_json::from_str(line).unwrap())
+                .collect()
+        };
+        assert_eq!(
+            lines(NdjsonNumbers::Exact),
+            [
+                json!({"PRICE": "12.50", "BIG": "123456789012345678901234567890"}),
+                json!({"PRICE": "-0.05", "BIG": 42}),
+            ]
+        );
+        assert_eq!(
+            lines(NdjsonNumbers::String),
+            [
+                json!({"PRICE": "12.50", "BIG": "123456789012345678901234567890"}),
+                json!({"PRICE": "-0.05", "BIG": "42"}),
+            ]
+        );
+        let numbers = ndjson(&result_set, NdjsonNumbers::Number);
+        assert!(
+            numbers.starts_with(r#"{"PRICE":12.50,"BIG":123456789012345678901234567890}"#),
+            "{numbers}"
+        );
+    }
+}
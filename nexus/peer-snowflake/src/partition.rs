@@ -0,0 +1,164 @@
+use std::{
+    future::Future,
+    pin::Pin,
+    sync::{Arc, Mutex, PoisonError},
+    task::{Context, Poll},
+};
+
+use chrono_tz::Tz;
+use futures::{Stream, StreamExt};
+use tokio::sync::mpsc;
+use tracing::info;
+
+use crate::{
+    SnowflakeClient, SnowflakeDecodeError, SnowflakeError, SnowflakeResultSet, SnowflakeValue,
+    client::{ResultSetRowType, decode_row},
+    decode::BinaryFormat,
+    retry::RetryBudget,
+};
+
+/// A row as sent by Snowflake: one raw string per column, `None` for SQL NULL.
+pub type SnowflakeRow = Vec<Option<String>>;
+
+type PartitionFetch =
+    Pin<Box<dyn Future<Output = Result<Vec<SnowflakeRow>, SnowflakeError>> + Send>>;
+
+/// Rows of every partition of a result set, fetched one partition at a time as the previous one
+/// is drained so that only a single partition is buffered.
+pub struct SnowflakeRowStream {
+    client: Arc<SnowflakeClient>,
+    statement_handle: String,
+    row_type: Vec<ResultSetRowType>,
+    timezone: Option<Tz>,
+    binary_format: BinaryFormat,
+    retry_budget: Arc<RetryBudget>,
+    partition_count: usize,
+    current_partition: usize,
+    rows: std::vec::IntoIter<SnowflakeRow>,
+    // the Mutex is only there to make the boxed future Sync; polling has `&mut self` so it is
+    // never contended
+    pending: Option<Mutex<PartitionFetch>>,
+    // the partition that takes the result past the client's limits, failing when reached
+    too_large: Option<(usize, SnowflakeError)>,
+    failed: bool,
+}
+
+impl SnowflakeRowStream {
+    pub(crate) fn new(result_set: SnowflakeResultSet, client: Arc<SnowflakeClient>) -> Self {
+        let too_large = client
+            .result_limits()
+            .first_exceeded(&result_set.resultSetMetaData.partitionInfo);
+        Self {
+            client,
+            partition_count: result_set.partition_count(),
+            statement_handle: result_set.statementHandle,
+            row_type: result_set.resultSetMetaData.rowType,
+            timezone: result_set.timezone,
+            binary_format: result_set.binary_format,
+            retry_budget: result_set.retry_budget,
+            current_partition: 0,
+            rows: result_set.data.into_iter(),
+            pending: None,
+            too_large,
+            failed: false,
+        }
+    }
+
+    pub fn statement_handle(&self) -> &str {
+        &self.statement_handle
+    }
+
+    pub fn partition_count(&self) -> usize {
+        self.partition_count
+    }
+
+    /// Decodes a row yielded by this stream into typed values, one per column.
+    pub fn decode_row(
+        &self,
+        row: &SnowflakeRow,
+    ) -> Result<Vec<SnowflakeValue>, SnowflakeDecodeError> {
+        decode_row(&self.row_type, row, self.timezone, self.binary_format)
+    }
+
+    /// Index of the partition rows are currently being read from (or fetched for).
+    pub fn current_partition(&self) -> usize {
+        self.current_partition
+    }
+}
+
+impl Stream for SnowflakeRowStream {
+    type Item = Result<SnowflakeRow, SnowflakeError>;
+
+    fn poll_next(mut self: Pin<&mut Self>, ctx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = &mut *self;
+        loop {
+            if this.failed {
+                return Poll::Ready(None);
+            }
+            if this
+                .too_large
+                .as_ref()
+                .is_some_and(|(partition, _)| *partition == this.current_partition)
+            {
+                this.failed = true;
+                return Poll::Ready(this.too_large.take().map(|(_, err)| Err(err)));
+            }
+            if let Some(pending) = this.pending.as_mut() {
+                let fetch = pending.get_mut().unwrap_or_else(PoisonError::into_inner);
+                match fetch.as_mut().poll(ctx) {
+                    Poll::Pending => return Poll::Pending,
+                    Poll::Ready(Ok(rows)) => {
+                        this.pending = None;
+                        this.rows = rows.into_iter();
+                    }
+                    Poll::Ready(Err(err)) => {
+                        this.pending = None;
+                        this.failed = true;
+                        return Poll::Ready(Some(Err(err)));
+                    }
+                }
+            }
+            if let Some(row) = this.rows.next() {
+                return Poll::Ready(Some(Ok(row)));
+            }
+            if this.current_partition + 1 >= this.partition_count {
+                return Poll::Ready(None);
+            }
+
+            this.current_partition += 1;
+            info!(
+                "Fetching partition {}/{} for statement with handle: {}",
+                this.current_partition + 1,
+                this.partition_count,
+                this.statement_handle
+            );
+            let client = this.client.clone();
+            let statement_handle = this.statement_handle.clone();
+            let partition = this.current_partition;
+            let retry_budget = this.retry_budget.clone();
+            this.pending = Some(Mutex::new(Box::pin(async move {
+                client
+                    .fetch_partition(&statement_handle, partition, &retry_budget)
+                    .await
+            })));
+        }
+    }
+}
+
+pub(crate) fn spawn_decoded_channel(
+    mut rows: SnowflakeRowStream,
+    capacity: usize,
+) -> mpsc::Receiver<Result<Vec<SnowflakeValue>, SnowflakeError>> {
+    let (sender, receiver) = mpsc::channel(capacity);
+    tokio::spawn(async move {
+        while let Some(row) = rows.next().await {
+            let decoded = row.and_then(|row| Ok(rows.decode_row(&row)?));
+            let failed = decoded.is_err();
+            // a closed channel means the receiver lost interest in the rest of the result
+            if sender.send(decoded).await.is_err() || failed {
+                return;
+            }
+        }
+    });
+    receiver
+}
@@ -0,0 +1,327 @@
+use std::{
+    collections::HashMap,
+    sync::{
+        Arc, Mutex, MutexGuard, PoisonError,
+        atomic::{AtomicUsize, Ordering},
+    },
+    time::Duration,
+};
+
+use pt::peerdb_peers::SnowflakeConfig;
+use tokio::{
+    sync::{Semaphore, SemaphorePermit},
+    task::JoinHandle,
+};
+use tokio_util::sync::CancellationToken;
+use tracing::{info, warn};
+
+use crate::{
+    ExecuteOptions, SnowflakeClient, SnowflakeCredential, SnowflakeError,
+    client::{self, StatementRef},
+};
+
+const POOL_IDLE_TIMEOUT: Duration = Duration::from_secs(90);
+const TCP_KEEPALIVE: Duration = Duration::from_secs(60);
+const DRAIN_POLL_INTERVAL: Duration = Duration::from_millis(50);
+// tag of keep-alive statements, to tell them apart in QUERY_HISTORY
+const KEEP_ALIVE_QUERY_TAG: &str = "peerdb_keep_alive";
+
+/// Caps the number of statements running at once, queueing the rest.
+pub(crate) struct ConcurrencyLimit {
+    semaphore: Semaphore,
+    max_in_flight: usize,
+    queued: AtomicUsize,
+    // submitted statements, keyed by URL, so a shutdown can cancel them
+    running: Mutex<HashMap<String, StatementRef>>,
+}
+
+/// Keeps a submitted statement in the running set until dropped.
+pub(crate) struct TrackedStatement<'a> {
+    limit: &'a ConcurrencyLimit,
+    url: String,
+}
+
+impl Drop for TrackedStatement<'_> {
+    fn drop(&mut self) {
+        self.limit.running().remove(&self.url);
+    }
+}
+
+// counts a caller as queued until it gets a permit or gives up waiting
+struct QueuedGuard<'a>(&'a AtomicUsize);
+
+impl Drop for QueuedGuard<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+impl ConcurrencyLimit {
+    fn new(max_in_flight: usize) -> Self {
+        Self {
+            semaphore: Semaphore::new(max_in_flight),
+            max_in_flight,
+            queued: AtomicUsize::new(0),
+            running: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Waits for a free slot, failing once the pool is shutting down.
+    pub(crate) async fn acquire(&self) -> Result<SemaphorePermit<'_>, SnowflakeError> {
+        self.queued.fetch_add(1, Ordering::Relaxed);
+        let _queued = QueuedGuard(&self.queued);
+        // the semaphore is only closed by a shutdown
+        self.semaphore
+            .acquire()
+            .await
+            .map_err(|_| SnowflakeError::ShuttingDown)
+    }
+
+    pub(crate) fn track(&self, statement: StatementRef) -> TrackedStatement<'_> {
+        let url = statement.url().to_string();
+        self.running().insert(url.clone(), statement);
+        TrackedStatement { limit: self, url }
+    }
+
+    fn running(&self) -> MutexGuard<'_, HashMap<String, StatementRef>> {
+        self.running.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn in_flight(&self) -> usize {
+        self.max_in_flight - self.semaphore.available_permits()
+    }
+
+    async fn drained(&self) {
+        // statements don't signal completion, but a shutdown can afford to poll
+        while self.in_flight() > 0 {
+            tokio::time::sleep(DRAIN_POLL_INTERVAL).await;
+        }
+    }
+}
+
+/// What became of the statements that were running when a pool was shut down.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ShutdownSummary {
+    /// Statements that finished within the grace period.
+    pub completed: usize,
+    /// Statements that Snowflake was asked to cancel after it.
+    pub cancelled: usize,
+}
+
+/// Hands out [`SnowflakeClient`]s that share one HTTP connection pool and at most
+/// `max_in_flight` running statements between them. Statements past the limit wait for a
+/// running one to finish.
+pub struct SnowflakeClientPool {
+    reqwest_client: reqwest::Client,
+    limit: Arc<ConcurrencyLimit>,
+    // stops the keep-alive tasks on shutdown
+    keep_alive: CancellationToken,
+}
+
+impl SnowflakeClientPool {
+    pub fn new(max_in_flight: usize) -> Result<Self, SnowflakeError> {
+        let reqwest_client = client::default_http_client()
+            .pool_idle_timeout(POOL_IDLE_TIMEOUT)
+            .pool_max_idle_per_host(max_in_flight)
+            .tcp_keepalive(TCP_KEEPALIVE)
+            .build()?;
+        Ok(SnowflakeClientPool::with_http_client(
+            max_in_flight,
+            reqwest_client,
+        ))
+    }
+
+    /// Same as [`SnowflakeClientPool::new`], with clients that send their requests through
+    /// `reqwest_client`, see [`SnowflakeClient::with_http_client`].
+    pub fn with_http_client(max_in_flight: usize, reqwest_client: reqwest::Client) -> Self {
+        Self {
+            reqwest_client,
+            limit: Arc::new(ConcurrencyLimit::new(max_in_flight)),
+            keep_alive: CancellationToken::new(),
+        }
+    }
+
+    pub fn client(
+        &self,
+        config: &SnowflakeConfig,
+        credential: Arc<dyn SnowflakeCredential>,
+    ) -> SnowflakeClient {
+        SnowflakeClient::with_http_client(config, credential, self.reqwest_client.clone())
+            .with_concurrency_limit(self.limit.clone())
+    }
+
+    /// Spawns a task that runs `SELECT 1` through `client` whenever it has been idle for
+    /// `interval`, so that its warehouse doesn't auto-suspend and the next statement doesn't
+    /// wait for it to resume. Pick an interval shorter than the warehouse's `AUTO_SUSPEND`.
+    /// The task stops when the pool shuts down.
+    pub fn keep_alive(&self, client: Arc<SnowflakeClient>, interval: Duration) -> JoinHandle<()> {
+        let cancellation = self.keep_alive.clone();
+        tokio::spawn(async move {
+            let options = ExecuteOptions::default().with_query_tag(KEEP_ALIVE_QUERY_TAG);
+            loop {
+                tokio::select! {
+                    _ = cancellation.cancelled() => return,
+                    _ = tokio::time::sleep(interval) => {}
+                }
+                // other statements are keeping the warehouse up already
+                if client
+                    .health_tracker()
+                    .idle_for()
+                    .is_some_and(|idle_for| idle_for < interval)
+                {
+                    continue;
+                }
+                match client.execute_with_options("SELECT 1", &options).await {
+                    Ok(_) => {}
+                    Err(err) if matches!(err.inner(), SnowflakeError::ShuttingDown) => return,
+                    Err(err) => warn!("Snowflake keep-alive failed: {}", err),
+                }
+            }
+        })
+    }
+
+    /// Statements currently running through clients of this pool.
+    pub fn in_flight(&self) -> usize {
+        self.limit.in_flight()
+    }
+
+    /// Statements waiting for one of the running statements to finish.
+    pub fn queued(&self) -> usize {
+        self.limit.queued.load(Ordering::Relaxed)
+    }
+
+    /// Stops clients of this pool from starting statements, waits up to `grace` for the running
+    /// ones to finish and cancels those that don't, so they stop using warehouse compute.
+    /// Queued and later statements fail with [`SnowflakeError::ShuttingDown`].
+    pub async fn shutdown(self, grace: Duration) -> ShutdownSummary {
+        self.keep_alive.cancel();
+        self.limit.semaphore.close();
+        let in_flight = self.limit.in_flight();
+        info!(
+            "Shutting down Snowflake client pool with {} running statements",
+            in_flight
+        );
+        if tokio::time::timeout(grace, self.limit.drained())
+            .await
+            .is_ok()
+        {
+            return ShutdownSummary {
+                completed: in_flight,
+                cancelled: 0,
+            };
+        }
+
+        let completed = in_flight.saturating_sub(self.limit.in_flight());
+        let running: Vec<_> = self.limit.running().values().cloned().collect();
+        let mut cancelled = 0;
+        for statement in running {
+            match statement.cancel().await {
+                Ok(()) => cancelled += 1,
+                Err(err) => warn!("Failed to cancel statement {}: {}", statement.url(), err),
+            }
+        }
+        ShutdownSummary {
+            completed,
+            cancelled,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::{MockSnowflakeServer, MockStatement, test_auth};
+
+    #[tokio::test]
+    async fn queues_statements_past_the_limit() {
+        let limit = Arc::new(ConcurrencyLimit::new(1));
+        let permit = limit.acquire().await.unwrap();
+        assert_eq!(
+            (limit.in_flight(), limit.queued.load(Ordering::Relaxed)),
+            (1, 0)
+        );
+
+        let waiter = tokio::spawn({
+            let limit = limit.clone();
+            async move {
+                let _permit = limit.acquire().await.unwrap();
+            }
+        });
+        while limit.queued.load(Ordering::Relaxed) == 0 {
+            tokio::task::yield_now().await;
+        }
+        assert_eq!(limit.in_flight(), 1);
+
+        drop(permit);
+        waiter.await.unwrap();
+        assert_eq!(
+            (limit.in_flight(), limit.queued.load(Ordering::Relaxed)),
+            (0, 0)
+        );
+    }
+
+    #[tokio::test]
+    async fn keeps_idle_clients_alive_until_shutdown() {
+        let server = MockSnowflakeServer::start().await;
+        server
+            .mount_statement(MockStatement::new("handle-1").partition(vec![vec![None]]))
+            .await;
+        let pool = SnowflakeClientPool::new(2).unwrap();
+        let client = Arc::new(
+            pool.client(&SnowflakeConfig::default(), test_auth("peerdb"))
+                .with_endpoint_url(server.endpoint_url()),
+        );
+
+        let keep_alive = pool.keep_alive(client, Duration::from_millis(20));
+        let submissions = || async { server.requests_to("/api/v2/statements").await };
+        while submissions().await.is_empty() {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        let body: serde_json::Value = submissions().await[0].body_json().unwrap();
+        assert_eq!(body["statement"], "SELECT 1");
+        assert_eq!(body["parameters"]["query_tag"], KEEP_ALIVE_QUERY_TAG);
+
+        pool.shutdown(Duration::from_millis(100)).await;
+        tokio::time::timeout(Duration::from_secs(1), keep_alive)
+            .await
+            .unwrap()
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn shutdown_cancels_statements_past_grace() {
+        let server = MockSnowflakeServer::start().await;
+        server
+            .mount_statement(MockStatement::new("handle-1").pending_polls(u64::MAX))
+            .await;
+        let pool = SnowflakeClientPool::new(2).unwrap();
+        let client = Arc::new(
+            pool.client(&SnowflakeConfig::default(), test_auth("peerdb"))
+                .with_endpoint_url(server.endpoint_url()),
+        );
+
+        let running = tokio::spawn({
+            let client = client.clone();
+            async move { client.execute("SELECT SYSTEM$WAIT(60)").await }
+        });
+        while pool.limit.running().is_empty() {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+
+        let limit = pool.limit.clone();
+        let summary = pool.shutdown(Duration::from_millis(100)).await;
+        assert_eq!(
+            summary,
+            ShutdownSummary {
+                completed: 0,
+                cancelled: 1
+            }
+        );
+        assert_eq!(server.requests_to("/handle-1/cancel").await.len(), 1);
+        assert!(matches!(
+            limit.acquire().await,
+            Err(SnowflakeError::ShuttingDown)
+        ));
+        running.abort();
+    }
+}
@@ -0,0 +1,231 @@
+use std::{
+    sync::atomic::{AtomicU32, Ordering},
+    time::Duration,
+};
+
+use reqwest::{
+    Response, StatusCode,
+    header::{HeaderMap, RETRY_AFTER},
+};
+
+/// How [`SnowflakeClient`](crate::SnowflakeClient) retries requests that Snowflake rejected
+/// with 429 or a 5xx, or that failed to connect or timed out.
+/// Other 4xx responses are never retried.
+#[derive(Clone, Debug)]
+pub struct RetryPolicy {
+    /// Total number of attempts, including the first one.
+    pub max_attempts: u32,
+    /// Delay before the first retry, doubled for every retry after it.
+    pub base_delay: Duration,
+    /// Upper bound on the delay between two attempts.
+    pub max_delay: Duration,
+    /// Sleep a uniformly random duration between zero and the backoff ("full jitter") so that
+    /// clients rate limited at the same time don't retry in lockstep.
+    pub jitter: bool,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_attempts: 5,
+            base_delay: Duration::from_millis(500),
+            max_delay: Duration::from_secs(30),
+            jitter: true,
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// A policy that sends every request exactly once.
+    pub fn never() -> Self {
+        Self {
+            max_attempts: 1,
+            ..Default::default()
+        }
+    }
+
+    pub(crate) fn is_retryable_status(status: StatusCode) -> bool {
+        status == StatusCode::TOO_MANY_REQUESTS || status.is_server_error()
+    }
+
+    pub(crate) fn retry_after(response: &Response) -> Option<Duration> {
+        RetryPolicy::parse_retry_after(response.headers())
+    }
+
+    // only the delay-seconds form; Snowflake doesn't send HTTP dates
+    fn parse_retry_after(headers: &HeaderMap) -> Option<Duration> {
+        headers
+            .get(RETRY_AFTER)?
+            .to_str()
+            .ok()?
+            .trim()
+            .parse()
+            .ok()
+            .map(Duration::from_secs)
+    }
+
+    /// Delay before retry number `retry` (starting at 1). A server-provided `Retry-After` wins
+    /// over the computed backoff, but is still capped by `max_delay`.
+    pub(crate) fn delay(&self, retry: u32, retry_after: Option<Duration>) -> Duration {
+        if let Some(retry_after) = retry_after {
+            return retry_after.min(self.max_delay);
+        }
+        let backoff = self
+            .base_delay
+            .saturating_mul(2u32.saturating_pow(retry.saturating_sub(1)))
+            .min(self.max_delay);
+        if self.jitter {
+            backoff.mul_f64(rand::random::<f64>())
+        } else {
+            backoff
+        }
+    }
+}
+
+/// Retries a statement gets unless the client sets
+/// [`SnowflakeClient::with_max_total_retries`](crate::SnowflakeClient::with_max_total_retries).
+pub(crate) const DEFAULT_MAX_TOTAL_RETRIES: u32 = 20;
+
+/// Retries left to the requests of one statement, from submitting it to fetching its last
+/// partition, so that a result with many partitions can't retry without bound even though each
+/// request stays within its [`RetryPolicy`].
+#[derive(Debug)]
+pub(crate) struct RetryBudget {
+    remaining: AtomicU32,
+}
+
+impl RetryBudget {
+    pub(crate) fn new(max_total_retries: u32) -> Self {
+        Self {
+            remaining: AtomicU32::new(max_total_retries),
+        }
+    }
+
+    /// Takes a retry from the budget, false if it is used up.
+    pub(crate) fn take(&self) -> bool {
+        self.remaining
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |n| n.checked_sub(1))
+            .is_ok()
+    }
+}
+
+impl Default for RetryBudget {
+    fn default() -> Self {
+        Self::new(DEFAULT_MAX_TOTAL_RETRIES)
+    }
+}
+
+/// How [`SnowflakeClient`](crate::SnowflakeClient) polls a statement that is still running
+/// after it was submitted. Every poll authorizes afresh, so a long statement picks up a
+/// refreshed JWT on its own.
+#[derive(Clone, Debug)]
+pub struct PollPolicy {
+    /// Wait before the second poll, the first one is sent right after submitting.
+    pub initial_interval: Duration,
+    /// Upper bound on the wait between two polls, which doubles after every poll up to this.
+    pub max_interval: Duration,
+    /// How long to poll before giving up with [`SnowflakeError::Timeout`], defaulting to the
+    /// statement timeout. Without either, polls go on until the statement completes.
+    ///
+    /// [`SnowflakeError::Timeout`]: crate::SnowflakeError::Timeout
+    pub max_wait: Option<Duration>,
+}
+
+impl Default for PollPolicy {
+    fn default() -> Self {
+        Self {
+            initial_interval: Duration::from_secs(1),
+            max_interval: Duration::from_secs(10),
+            max_wait: None,
+        }
+    }
+}
+
+impl PollPolicy {
+    /// Wait before poll number `poll + 1`, `poll` starting at 1.
+    pub(crate) fn interval(&self, poll: u32) -> Duration {
+        self.initial_interval
+            .saturating_mul(2u32.saturating_pow(poll.saturating_sub(1)))
+            .min(self.max_interval)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use reqwest::header::HeaderValue;
+
+    use super::*;
+
+    #[test]
+    fn backs_off_exponentially_up_to_max_delay() {
+        let policy = RetryPolicy {
+            max_attempts: 10,
+            base_delay: Duration::from_millis(100),
+            max_delay: Duration::from_secs(1),
+            jitter: false,
+        };
+        let delays: Vec<_> = (1..=6).map(|retry| policy.delay(retry, None)).collect();
+        assert_eq!(
+            delays,
+            [100, 200, 400, 800, 1000, 1000].map(Duration::from_millis)
+        );
+    }
+
+    #[test]
+    fn budgets_retries() {
+        let budget = RetryBudget::new(2);
+        assert_eq!([(); 3].map(|_| budget.take()), [true, true, false]);
+        let budget = RetryBudget::default();
+        assert_eq!(
+            (0..100).filter(|_| budget.take()).count(),
+            DEFAULT_MAX_TOTAL_RETRIES as usize
+        );
+    }
+
+    #[test]
+    fn polls_less_often_up_to_max_interval() {
+        let intervals: Vec<_> = (1..=6)
+            .map(|poll| PollPolicy::default().interval(poll).as_secs())
+            .collect();
+        assert_eq!(intervals, [1, 2, 4, 8, 10, 10]);
+    }
+
+    #[test]
+    fn jitter_stays_within_backoff() {
+        let policy = RetryPolicy::default();
+        let unjittered = RetryPolicy {
+            jitter: false,
+            ..policy.clone()
+        };
+        for retry in 1..=5 {
+            assert!(policy.delay(retry, None) <= unjittered.delay(retry, None));
+        }
+    }
+
+    #[test]
+    fn honors_retry_after() {
+        let mut headers = HeaderMap::new();
+        headers.insert(RETRY_AFTER, HeaderValue::from_static("7"));
+        let retry_after = RetryPolicy::parse_retry_after(&headers);
+        assert_eq!(retry_after, Some(Duration::from_secs(7)));
+
+        let policy = RetryPolicy::default();
+        assert_eq!(policy.delay(1, retry_after), Duration::from_secs(7));
+        assert_eq!(
+            policy.delay(1, Some(Duration::from_secs(120))),
+            policy.max_delay
+        );
+    }
+
+    #[test]
+    fn only_retries_rate_limits_and_server_errors() {
+        assert!(RetryPolicy::is_retryable_status(
+            StatusCode::TOO_MANY_REQUESTS
+        ));
+        assert!(RetryPolicy::is_retryable_status(
+            StatusCode::SERVICE_UNAVAILABLE
+        ));
+        assert!(!RetryPolicy::is_retryable_status(StatusCode::BAD_REQUEST));
+        assert!(!RetryPolicy::is_retryable_status(StatusCode::UNAUTHORIZED));
+    }
+}
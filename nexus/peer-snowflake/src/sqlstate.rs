@@ -0,0 +1,102 @@
+//! Translation of the SQLSTATEs in Snowflake errors to the ones Postgres clients expect.
+
+use pgwire::error::{ErrorInfo, PgWireError};
+
+use crate::SnowflakeError;
+
+/// Snowflake's ODBC-style and other non-Postgres SQLSTATEs, with their Postgres equivalent.
+const SNOWFLAKE_SQLSTATES: &[(&str, &str)] = &[
+    // base table or view not found
+    ("42S02", "42P01"),
+    // base table or view already exists
+    ("42S01", "42P07"),
+    // column not found
+    ("42S22", "42703"),
+    // invalid character value for cast
+    ("22018", "22P02"),
+];
+
+/// SQLSTATEs Snowflake sends that mean the same in Postgres.
+const SHARED_SQLSTATES: &[&str] = &[
+    "0A000", "22001", "22003", "22007", "22008", "22012", "22023", "23502", "23505", "25000",
+    "28000", "40001", "42000", "42501", "42601", "42703", "42710", "53200", "57014",
+];
+
+/// The generic Postgres state of each class, for states that aren't known individually.
+const SQLSTATE_CLASSES: &[&str] = &[
+    "08000", "0A000", "22000", "23000", "25000", "28000", "40000", "42000", "53000", "57000",
+];
+
+/// Postgres SQLSTATE for the SQLSTATE `state` of a Snowflake error. States without an exact
+/// equivalent keep their class (e.g. `22xxx` data exceptions become `22000`), and anything
+/// else is `XX000`.
+pub fn snowflake_sqlstate_to_pg(state: &str) -> &'static str {
+    if let Some(&(_, pg_state)) = SNOWFLAKE_SQLSTATES.iter().find(|(sf, _)| *sf == state) {
+        return pg_state;
+    }
+    if let Some(&pg_state) = SHARED_SQLSTATES.iter().find(|pg| **pg == state) {
+        return pg_state;
+    }
+    if state.len() == 5
+        && let Some(class) = state.get(..2)
+        && let Some(&pg_state) = SQLSTATE_CLASSES.iter().find(|pg| pg.starts_with(class))
+    {
+        return pg_state;
+    }
+    "XX000"
+}
+
+/// The wire error for `err`: an `ErrorResponse` with the translated SQLSTATE when Snowflake
+/// reported one, an internal error otherwise.
+pub(crate) fn pg_wire_error(err: SnowflakeError) -> PgWireError {
+    let Some(api_error) = err.api_error() else {
+        return PgWireError::ApiError(err.into());
+    };
+    let Some(sql_state) = &api_error.sql_state else {
+        return PgWireError::ApiError(err.into());
+    };
+    PgWireError::UserError(Box::new(ErrorInfo::new(
+        "ERROR".to_owned(),
+        snowflake_sqlstate_to_pg(sql_state).to_owned(),
+        api_error.message.clone(),
+    )))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn maps_sqlstates_to_postgres() {
+        let cases = [
+            ("42S02", "42P01"),
+            ("42000", "42000"),
+            ("42601", "42601"),
+            ("22012", "22012"),
+            ("22018", "22P02"),
+            ("22P99", "22000"),
+            ("57014", "57014"),
+            ("HY000", "XX000"),
+            ("", "XX000"),
+        ];
+        for (state, expected) in cases {
+            assert_eq!(snowflake_sqlstate_to_pg(state), expected, "{state}");
+        }
+    }
+
+    #[test]
+    fn reports_sqlstate_on_the_wire() {
+        let body =
+            r#"{"code": "002003", "message": "Object 'T' does not exist.", "sqlState": "42S02"}"#;
+        let err = SnowflakeError::from_response(
+            reqwest::StatusCode::UNPROCESSABLE_ENTITY,
+            body.to_string(),
+        );
+        let PgWireError::UserError(info) = pg_wire_error(err) else {
+            panic!("expected an ErrorResponse");
+        };
+        let info = format!("{info:?}");
+        assert!(info.contains(r#""42P01""#), "{info}");
+        assert!(info.contains("Object 'T' does not exist."), "{info}");
+    }
+}
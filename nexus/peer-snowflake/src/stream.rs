@@ -1,75 +1,42 @@
-use crate::{PartitionResult, ResultSet, auth::SnowflakeAuth};
-use chrono::{DateTime, NaiveDate, NaiveDateTime, NaiveTime, TimeZone, Utc};
-use futures::Stream;
+use crate::{
+    ColumnMeta, SnowflakeClient, SnowflakeResultSet, SnowflakeValue,
+    bind::format_fixed,
+    partition::{SnowflakeRow, SnowflakeRowStream},
+    sqlstate,
+    types::snowflake_type_to_pg_oid,
+};
+use futures::{Stream, StreamExt};
 use peer_cursor::{Record, RecordStream, Schema};
 use pgwire::{
-    api::{
-        Type,
-        results::{FieldFormat, FieldInfo},
-    },
+    api::results::{FieldFormat, FieldInfo},
     error::{PgWireError, PgWireResult},
 };
-use secrecy::ExposeSecret;
-use serde::Deserialize;
+use rust_decimal::Decimal;
 use std::{
     pin::Pin,
     sync::Arc,
     task::{Context, Poll},
 };
-use value::Value::{
-    self, BigInt, Binary, Bool, Date, Float, PostgresTimestamp, Text, Time, TimestampWithTimeZone,
-};
-
-#[derive(Clone, Deserialize, Debug)]
-#[serde(rename_all = "lowercase")]
-pub(crate) enum SnowflakeDataType {
-    Fixed,
-    Real,
-    Text,
-    Binary,
-    Boolean,
-    Date,
-    Time,
-    #[serde(rename = "timestamp_ltz")]
-    TimestampLtz,
-    #[serde(rename = "timestamp_ntz")]
-    TimestampNtz,
-    #[serde(rename = "timestamp_tz")]
-    TimestampTz,
-    Variant,
-}
+use value::Value;
 
 #[derive(Clone)]
 pub struct SnowflakeSchema {
     schema: Schema,
 }
 
-fn convert_field_type(field_type: &SnowflakeDataType) -> Type {
-    match field_type {
-        SnowflakeDataType::Fixed => Type::NUMERIC,
-        SnowflakeDataType::Real => Type::FLOAT8,
-        SnowflakeDataType::Text => Type::TEXT,
-        SnowflakeDataType::Binary => Type::BYTEA,
-        SnowflakeDataType::Boolean => Type::BOOL,
-        SnowflakeDataType::Date => Type::DATE,
-        SnowflakeDataType::Time => Type::TIME,
-        SnowflakeDataType::TimestampLtz => Type::TIMESTAMPTZ,
-        SnowflakeDataType::TimestampNtz => Type::TIMESTAMP,
-        SnowflakeDataType::TimestampTz => Type::TIMESTAMPTZ,
-        SnowflakeDataType::Variant => Type::JSONB,
-    }
-}
-
 impl SnowflakeSchema {
-    pub fn from_result_set(result_set: &ResultSet) -> Self {
-        let fields = result_set.resultSetMetaData.rowType.clone();
+    pub fn from_result_set(result_set: &SnowflakeResultSet) -> Self {
+        SnowflakeSchema::from_columns(&result_set.columns())
+    }
 
+    /// The schema of a result with `columns`, e.g. as found by [`SnowflakeClient::describe`].
+    pub fn from_columns(columns: &[ColumnMeta]) -> Self {
         let schema = Arc::new(
-            fields
+            columns
                 .iter()
-                .map(|field| {
-                    let datatype = convert_field_type(&field.r#type);
-                    FieldInfo::new(field.name.clone(), None, None, datatype, FieldFormat::Text)
+                .map(|column| {
+                    let datatype = snowflake_type_to_pg_oid(column);
+                    FieldInfo::new(column.name.clone(), None, None, datatype, FieldFormat::Text)
                 })
                 .collect(),
         );
@@ -88,30 +55,19 @@ pub struct SnowflakeRecordStream {
 }
 
 pub struct SnowflakeRecordStreamInner {
-    result_set: ResultSet,
-    partition_index: usize,
-    partition_number: usize,
-    endpoint_url: String,
-    auth: SnowflakeAuth,
+    rows: SnowflakeRowStream,
     schema: SnowflakeSchema,
 }
 
 impl SnowflakeRecordStream {
     pub fn new(
-        result_set: ResultSet,
-        partition_index: usize,
-        partition_number: usize,
-        endpoint_url: String,
-        auth: SnowflakeAuth,
+        result_set: SnowflakeResultSet,
+        client: Arc<SnowflakeClient>,
     ) -> SnowflakeRecordStream {
         let schema = SnowflakeSchema::from_result_set(&result_set);
 
         let inner = SnowflakeRecordStreamInner {
-            result_set,
-            partition_index,
-            partition_number,
-            endpoint_url,
-            auth,
+            rows: result_set.into_rows(client),
             schema: schema.clone(),
         };
         let stream = futures::stream::unfold(inner, async |mut inner| {
@@ -126,138 +82,50 @@ impl SnowflakeRecordStream {
 }
 
 impl SnowflakeRecordStreamInner {
-    pub fn convert_result_set_item(&mut self) -> anyhow::Result<Record> {
-        let mut row_values = Vec::new();
-
-        for (index, value) in self.result_set.data[self.partition_index]
-            .iter()
-            .enumerate()
-        {
-            const DATE_PARSE_FORMAT: &str = "%Y/%m/%d";
-            const TIME_PARSE_FORMAT: &str = "%H:%M:%S.%9f";
-            const TIMESTAMP_PARSE_FORMAT: &str = "%FT%T.%9f";
-            const TIMESTAMP_TZ_PARSE_FORMAT: &str = "%FT%T%.9f%z";
-            let row_value = match value {
-                None => None,
-                Some(elem) => Some(
-                    match self.result_set.resultSetMetaData.rowType[index].r#type {
-                        SnowflakeDataType::Fixed => match elem.parse::<i64>() {
-                            Ok(_) => BigInt(elem.parse()?),
-                            Err(_) => Text(elem.to_string()),
-                        },
-                        SnowflakeDataType::Real => Float(elem.parse()?),
-                        SnowflakeDataType::Text => Text(elem.to_string()),
-                        SnowflakeDataType::Binary => Binary(hex::decode(elem)?.into()),
-                        SnowflakeDataType::Boolean => Bool(elem.parse()?),
-                        SnowflakeDataType::Date => {
-                            println!("Entered Date. elem: {:#?}", elem);
-                            Date(NaiveDate::parse_from_str(elem, DATE_PARSE_FORMAT)?)
-                        }
-                        SnowflakeDataType::Time => {
-                            Time(NaiveTime::parse_from_str(elem, TIME_PARSE_FORMAT)?)
-                        }
-                        // really hacky workaround for parsing the UTC timezone specifically.
-                        SnowflakeDataType::TimestampLtz => {
-                            match DateTime::parse_from_str(elem, TIMESTAMP_TZ_PARSE_FORMAT) {
-                                Ok(_) => TimestampWithTimeZone(
-                                    Utc.from_utc_datetime(
-                                        &DateTime::parse_from_str(elem, TIMESTAMP_TZ_PARSE_FORMAT)?
-                                            .naive_utc(),
-                                    ),
-                                ),
-                                Err(_) => TimestampWithTimeZone(
-                                    Utc.from_utc_datetime(
-                                        &DateTime::parse_from_str(
-                                            &elem.replace('Z', "+0000"),
-                                            TIMESTAMP_TZ_PARSE_FORMAT,
-                                        )?
-                                        .naive_utc(),
-                                    ),
-                                ),
-                            }
-                        }
-                        SnowflakeDataType::TimestampNtz => PostgresTimestamp(
-                            NaiveDateTime::parse_from_str(elem, TIMESTAMP_PARSE_FORMAT)?,
-                        ),
-                        SnowflakeDataType::TimestampTz => {
-                            match DateTime::parse_from_str(elem, TIMESTAMP_TZ_PARSE_FORMAT) {
-                                Ok(_) => TimestampWithTimeZone(
-                                    Utc.from_utc_datetime(
-                                        &DateTime::parse_from_str(elem, TIMESTAMP_TZ_PARSE_FORMAT)?
-                                            .naive_utc(),
-                                    ),
-                                ),
-                                Err(_) => TimestampWithTimeZone(
-                                    Utc.from_utc_datetime(
-                                        &DateTime::parse_from_str(
-                                            &elem.replace('Z', "+0000"),
-                                            TIMESTAMP_TZ_PARSE_FORMAT,
-                                        )?
-                                        .naive_utc(),
-                                    ),
-                                ),
-                            }
-                        }
-                        SnowflakeDataType::Variant => {
-                            let jsonb: serde_json::Value = serde_json::from_str(elem)?;
-                            Value::JsonB(jsonb)
-                        }
-                    },
-                ),
-            };
-
-            row_values.push(row_value.unwrap_or(Value::Null));
-        }
-
-        self.partition_index += 1;
-
+    pub fn convert_result_set_item(&self, row: SnowflakeRow) -> anyhow::Result<Record> {
+        let values = self.rows.decode_row(&row)?;
         Ok(Record {
-            values: row_values,
+            values: values.into_iter().map(pg_value).collect(),
             schema: self.schema.schema(),
         })
     }
 
-    async fn advance_partition(&mut self) -> anyhow::Result<bool> {
-        if (self.partition_number + 1) == self.result_set.resultSetMetaData.partitionInfo.len() {
-            return Ok(false);
-        }
-        self.partition_number += 1;
-        self.partition_index = 0;
-        let partition_number = self.partition_number;
-        let secret = self.auth.get_jwt()?.expose_secret();
-        let statement_handle = self.result_set.statementHandle.clone();
-        let url = self.endpoint_url.clone();
-        println!("Secret: {:#?}", secret);
-        let response = reqwest::Client::new()
-            .get(format!("{}/{}", url, statement_handle))
-            .query(&[("partition", partition_number.to_string())])
-            .header("Authorization", format!("Bearer {}", secret))
-            .header("X-Snowflake-Authorization-Token-Type", "KEYPAIR_JWT")
-            .header("user-agent", "ureq")
-            .send()
-            .await?
-            .json::<PartitionResult>()
-            .await
-            .map_err(|_| anyhow::anyhow!("get_partition failed"))?;
-        println!("Response: {:#?}", response.data);
-
-        self.result_set.data = response.data;
-        Ok(true)
+    async fn advance(&mut self) -> Option<PgWireResult<Record>> {
+        let record = match self.rows.next().await? {
+            Ok(row) => self.convert_result_set_item(row),
+            Err(err) => return Some(Err(sqlstate::pg_wire_error(err))),
+        };
+        Some(record.map_err(|e| PgWireError::ApiError(e.into())))
     }
+}
 
-    async fn advance(&mut self) -> Option<PgWireResult<Record>> {
-        let next = self.partition_index < self.result_set.data.len() || {
-            match self.advance_partition().await {
-                Ok(val) => val,
-                Err(err) => return Some(Err(PgWireError::ApiError(err.into()))),
+/// The value of a decoded cell in the type [`snowflake_type_to_pg_oid`] advertises for its column.
+/// Numbers too wide for an int8 or a [`Decimal`] are sent as their exact text, which is what a
+/// `numeric` looks like in the text format the fields are in.
+fn pg_value(value: SnowflakeValue) -> Value {
+    match value {
+        SnowflakeValue::Null => Value::Null,
+        SnowflakeValue::Number { value, scale } => {
+            if scale == 0
+                && let Ok(value) = i64::try_from(value)
+            {
+                return Value::BigInt(value);
+            }
+            match Decimal::try_from_i128_with_scale(value, scale) {
+                Ok(decimal) => Value::Numeric(decimal),
+                Err(_) => Value::Text(format_fixed(value, scale)),
             }
-        };
-        if next {
-            let record = self.convert_result_set_item();
-            Some(record.map_err(|e| PgWireError::ApiError(e.into())))
-        } else {
-            None
         }
+        SnowflakeValue::Real(value) => Value::Double(value),
+        SnowflakeValue::Text(text) | SnowflakeValue::Geo(text) => Value::Text(text),
+        SnowflakeValue::Boolean(value) => Value::Bool(value),
+        SnowflakeValue::Date(date) => Value::Date(date),
+        SnowflakeValue::Time(time) => Value::Time(time),
+        SnowflakeValue::TimestampNtz(timestamp) => Value::PostgresTimestamp(timestamp),
+        SnowflakeValue::TimestampLtz(timestamp) => Value::TimestampWithTimeZone(timestamp),
+        SnowflakeValue::TimestampTz(timestamp) => Value::TimestampWithTimeZone(timestamp.to_utc()),
+        SnowflakeValue::Binary(bytes) => Value::Binary(bytes.into()),
+        SnowflakeValue::Variant(json) => Value::JsonB(json),
     }
 }
 
@@ -274,3 +142,130 @@ impl RecordStream for SnowflakeRecordStream {
         self.schema.schema()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use chrono::{DateTime, NaiveDate};
+    use postgres_types::Type;
+    use pt::peerdb_peers::SnowflakeConfig;
+    use serde_json::json;
+
+    use super::*;
+    use crate::{mock::test_auth, types::SnowflakeDataType};
+
+    fn column(name: &str, data_type: SnowflakeDataType, scale: Option<u32>) -> ColumnMeta {
+        ColumnMeta {
+            name: name.to_string(),
+            data_type,
+            nullable: true,
+            precision: None,
+            scale,
+        }
+    }
+
+    #[test]
+    fn maps_described_columns_to_pg_fields() {
+        let schema = SnowflakeSchema::from_columns(&[
+            column("ID", SnowflakeDataType::Fixed, Some(0)),
+            column("PRICE", SnowflakeDataType::Fixed, Some(2)),
+            column("NAME", SnowflakeDataType::Text, None),
+        ])
+        .schema();
+        let fields: Vec<_> = schema
+            .iter()
+            .map(|field| (field.name(), field.datatype().clone()))
+            .collect();
+        assert_eq!(
+            fields,
+            [
+                ("ID", Type::INT8),
+                ("PRICE", Type::NUMERIC),
+                ("NAME", Type::TEXT)
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn streams_decoded_values() {
+        let columns = [
+            ("ID", "fixed", 0),
+            ("PRICE", "fixed", 2),
+            ("WIDE", "fixed", 0),
+            ("SCORE", "real", 0),
+            ("NTZ", "timestamp_ntz", 9),
+            ("LTZ", "timestamp_ltz", 9),
+            ("TZ", "timestamp_tz", 9),
+            ("DAY", "date", 0),
+            ("NOTE", "text", 0),
+        ];
+        let row_type: Vec<_> = columns
+            .iter()
+            .map(|(name, r#type, scale)| json!({"name": name, "type": r#type, "scale": scale}))
+            .collect();
+        let mut result_set: SnowflakeResultSet = serde_json::from_value(json!({
+            "statementHandle": "handle-1",
+            "data": [[
+                "42",
+                "12.50",
+                "12345678901234567890123456789012345678",
+                "1.5",
+                "1625097600.123456789",
+                "2021-06-30T13:45:00.000000000",
+                "[1625097600, 0, 1560]",
+                "18808",
+                null
+            ]],
+            "resultSetMetaData": {
+                "partitionInfo": [{"rowCount": 1, "uncompressedSize": 0}],
+                "rowType": row_type,
+            },
+        }))
+        .unwrap();
+        result_set.timezone = Some(chrono_tz::Europe::Berlin);
+        let client =
+            SnowflakeClient::new(&SnowflakeConfig::default(), test_auth("peerdb")).unwrap();
+
+        let records: Vec<_> = SnowflakeRecordStream::new(result_set, Arc::new(client))
+            .collect()
+            .await;
+        let [Ok(record)] = &records[..] else {
+            panic!("expected one record");
+        };
+        let utc = |seconds, nanos| DateTime::from_timestamp(seconds, nanos).unwrap();
+        assert_eq!(
+            record.values,
+            [
+                Value::BigInt(42),
+                Value::Numeric(Decimal::new(1250, 2)),
+                Value::Text("12345678901234567890123456789012345678".to_string()),
+                Value::Double(1.5),
+                Value::PostgresTimestamp(utc(1_625_097_600, 123_456_789).naive_utc()),
+                // a wall-clock time in the session timezone, CEST
+                Value::TimestampWithTimeZone(utc(1_625_053_500, 0)),
+                Value::TimestampWithTimeZone(utc(1_625_097_600, 0)),
+                Value::Date(NaiveDate::from_ymd_opt(2021, 6, 30).unwrap()),
+                Value::Null,
+            ]
+        );
+        assert_eq!(record.schema[1].datatype(), &Type::NUMERIC);
+    }
+
+    #[tokio::test]
+    async fn fails_rows_that_dont_decode() {
+        let result_set: SnowflakeResultSet = serde_json::from_value(json!({
+            "statementHandle": "handle-1",
+            "data": [["not a date"]],
+            "resultSetMetaData": {
+                "partitionInfo": [{"rowCount": 1, "uncompressedSize": 0}],
+                "rowType": [{"name": "DAY", "type": "date"}],
+            },
+        }))
+        .unwrap();
+        let client =
+            SnowflakeClient::new(&SnowflakeConfig::default(), test_auth("peerdb")).unwrap();
+
+        let mut records = SnowflakeRecordStream::new(result_set, Arc::new(client));
+        let err = records.next().await.unwrap().err().unwrap();
+        assert!(err.to_string().contains("column DAY"), "{err}");
+    }
+}
@@ -0,0 +1,150 @@
+//! A local syntax check for generated SQL, to catch malformed statements before they are sent.
+
+// Snowflake's limit on the length of an identifier
+const MAX_IDENTIFIER_LEN: usize = 255;
+
+use sqlparser::{
+    dialect::SnowflakeDialect,
+    parser::Parser,
+    tokenizer::{Token, Tokenizer},
+};
+
+/// A statement that failed to parse.
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+#[error("statement {statement}: {message}")]
+pub struct ParseError {
+    /// 0-based position of the statement in the checked SQL.
+    pub statement: usize,
+    pub message: String,
+}
+
+/// Parses the `;`-separated statements in `sql` with a Snowflake dialect, without running
+/// them, and returns the error of every statement that doesn't parse.
+///
+/// This is only a syntax check: names, types and privileges are not looked at, and the
+/// parser is stricter than Snowflake about some syntax, so a statement rejected here may still
+/// run. It is meant for SQL PeerDB generates itself, e.g. `MERGE` and `COPY INTO`, not as
+/// a gate on user queries.
+pub fn validate_sql(sql: &str) -> Result<(), Vec<ParseError>> {
+    let dialect = SnowflakeDialect {};
+    let tokens = Tokenizer::new(&dialect, sql).tokenize().map_err(|err| {
+        vec![ParseError {
+            statement: 0,
+            message: err.to_string(),
+        }]
+    })?;
+
+    // parse statements one by one so that one bad statement doesn't hide the others
+    let errors: Vec<_> = tokens
+        .split(|token| *token == Token::SemiColon)
+        .filter(|statement| {
+            statement
+                .iter()
+                .any(|token| !matches!(token, Token::Whitespace(_)))
+        })
+        .enumerate()
+        .filter_map(|(idx, statement)| {
+            let statement: String = statement.iter().map(Token::to_string).collect();
+            Parser::parse_sql(&dialect, &statement)
+                .err()
+                .map(|err| ParseError {
+                    statement: idx,
+                    message: err.to_string(),
+                })
+        })
+        .collect();
+    if errors.is_empty() {
+        Ok(())
+    } else {
+        Err(errors)
+    }
+}
+
+/// Whether `name` is a Snowflake identifier: unquoted, made of letters, digits, `_` and `$`
+/// and not starting with a digit or `$`, or double-quoted with inner quotes doubled.
+pub(crate) fn is_valid_identifier(name: &str) -> bool {
+    if let Some(quoted) = name
+        .strip_prefix('"')
+        .and_then(|name| name.strip_suffix('"'))
+    {
+        return !quoted.is_empty()
+            && quoted.chars().count() <= MAX_IDENTIFIER_LEN
+            && !quoted.replace("\"\"", "").contains('"');
+    }
+    let mut chars = name.chars();
+    name.len() <= MAX_IDENTIFIER_LEN
+        && chars
+            .next()
+            .is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '$')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MERGE: &str = "MERGE INTO users t USING users_staging s ON t.id = s.id \
+        WHEN MATCHED AND s._peerdb_deleted THEN DELETE \
+        WHEN MATCHED THEN UPDATE SET t.name = s.name \
+        WHEN NOT MATCHED THEN INSERT (id, name) VALUES (s.id, s.name)";
+    const COPY: &str =
+        "COPY INTO users_staging FROM @peerdb_stage/batch_42 FILE_FORMAT = (TYPE = 'AVRO')";
+
+    #[test]
+    fn accepts_valid_merge_and_copy() {
+        assert_eq!(validate_sql(MERGE), Ok(()));
+        assert_eq!(validate_sql(COPY), Ok(()));
+        assert_eq!(validate_sql(&format!("{COPY};\n{MERGE};\n")), Ok(()));
+    }
+
+    #[test]
+    fn rejects_malformed_merge_and_copy() {
+        let statements = [
+            // no join condition
+            "MERGE INTO users t USING users_staging s WHEN MATCHED THEN DELETE",
+            // UPDATE without SET
+            "MERGE INTO users t USING users_staging s ON t.id = s.id \
+             WHEN MATCHED THEN UPDATE t.name = s.name",
+            // no FROM
+            "COPY INTO users_staging @peerdb_stage/batch_42",
+        ];
+        for sql in statements {
+            assert!(validate_sql(sql).is_err(), "{sql}");
+        }
+    }
+
+    #[test]
+    fn reports_every_failing_statement() {
+        let sql = format!("{MERGE} WHEN; SELECT 1; COPY INTO t @stage");
+        let errors = validate_sql(&sql).unwrap_err();
+        let failed: Vec<_> = errors.iter().map(|err| err.statement).collect();
+        assert_eq!(failed, [0, 2]);
+    }
+
+    #[test]
+    fn checks_identifiers() {
+        let valid = [
+            "COMPUTE_XL",
+            "etl_wh",
+            "_tmp$1",
+            r#""Heavy Analytics""#,
+            r#""a""b""#,
+        ];
+        for name in valid {
+            assert!(is_valid_identifier(name), "{name}");
+        }
+        let too_long = "W".repeat(256);
+        let invalid = [
+            "",
+            "1WH",
+            "$WH",
+            "WH; DROP TABLE t",
+            "wh-1",
+            r#""""#,
+            r#""a"b""#,
+        ];
+        for name in invalid.into_iter().chain([too_long.as_str()]) {
+            assert!(!is_valid_identifier(name), "{name}");
+        }
+    }
+}
@@ -0,0 +1,55 @@
+//! RSA keys for tests, generated from a fixed seed so that every run signs with the same key.
+
+use std::sync::OnceLock;
+
+use base64::prelude::*;
+use rand_chacha::{ChaCha8Rng, rand_core::SeedableRng};
+use rsa::{
+    RsaPrivateKey,
+    pkcs8::{EncodePrivateKey, EncodePublicKey, LineEnding},
+};
+use sha2::{Digest, Sha256};
+
+// Snowflake only accepts RSA keys of at least 2048 bits
+const TEST_KEY_BITS: usize = 2048;
+const TEST_KEY_SEED: u64 = 0x5EED;
+
+pub(crate) struct TestKey {
+    pub(crate) private_key: RsaPrivateKey,
+    /// Unencrypted PKCS#8 PEM of `private_key`.
+    pub(crate) pem: String,
+    /// `SHA256:<base64>` fingerprint of the public key, worked out without `SnowflakeAuth`.
+    pub(crate) fingerprint: String,
+}
+
+impl TestKey {
+    /// Generating a key takes a while in debug builds, prefer the shared [`rsa_test_key`].
+    pub(crate) fn generate(seed: u64) -> Self {
+        let mut rng = ChaCha8Rng::seed_from_u64(seed);
+        let private_key =
+            RsaPrivateKey::new(&mut rng, TEST_KEY_BITS).expect("failed to generate test key");
+        let pem = private_key
+            .to_pkcs8_pem(LineEnding::LF)
+            .expect("failed to encode test key")
+            .to_string();
+        let public_key = private_key
+            .to_public_key()
+            .to_public_key_der()
+            .expect("failed to encode test public key");
+        let fingerprint = format!(
+            "SHA256:{}",
+            BASE64_STANDARD.encode(Sha256::digest(public_key.as_bytes()))
+        );
+        Self {
+            private_key,
+            pem,
+            fingerprint,
+        }
+    }
+}
+
+/// The key of the default seed, generated once and shared by every test.
+pub(crate) fn rsa_test_key() -> &'static TestKey {
+    static KEY: OnceLock<TestKey> = OnceLock::new();
+    KEY.get_or_init(|| TestKey::generate(TEST_KEY_SEED))
+}
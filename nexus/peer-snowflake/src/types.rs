@@ -0,0 +1,212 @@
+use pgwire::api::Type;
+use postgres_types::Kind;
+use serde::Deserialize;
+use tracing::warn;
+
+use crate::ColumnMeta;
+
+/// Column types as named in `resultSetMetaData.rowType`.
+#[derive(Clone, Copy, Deserialize, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum SnowflakeDataType {
+    Fixed,
+    Real,
+    Text,
+    Binary,
+    Boolean,
+    Date,
+    Time,
+    #[serde(rename = "timestamp_ltz")]
+    TimestampLtz,
+    #[serde(rename = "timestamp_ntz")]
+    TimestampNtz,
+    #[serde(rename = "timestamp_tz")]
+    TimestampTz,
+    Variant,
+    Object,
+    Array,
+    Geography,
+    Geometry,
+    /// A type this crate doesn't know about yet.
+    #[serde(other)]
+    Unknown,
+}
+
+// every integer of up to 18 digits fits into an int8, but not every one of 19
+const MAX_INT8_PRECISION: u32 = 18;
+
+/// Postgres type advertised for `col` in the RowDescription of a proxied query. Integers are
+/// `int8` only if their precision guarantees they fit, so `NUMBER(38, 0)`, the precision of
+/// `INT` and of a bare `NUMBER`, is `numeric`.
+pub fn snowflake_type_to_pg_oid(col: &ColumnMeta) -> Type {
+    match col.data_type {
+        SnowflakeDataType::Fixed
+            if col.scale.unwrap_or(0) == 0
+                && col
+                    .precision
+                    .is_none_or(|precision| precision <= MAX_INT8_PRECISION) =>
+        {
+            Type::INT8
+        }
+        SnowflakeDataType::Fixed => Type::NUMERIC,
+        SnowflakeDataType::Real => Type::FLOAT8,
+        SnowflakeDataType::Text => Type::TEXT,
+        SnowflakeDataType::Binary => Type::BYTEA,
+        SnowflakeDataType::Boolean => Type::BOOL,
+        SnowflakeDataType::Date => Type::DATE,
+        SnowflakeDataType::Time => Type::TIME,
+        SnowflakeDataType::TimestampLtz => Type::TIMESTAMPTZ,
+        SnowflakeDataType::TimestampNtz => Type::TIMESTAMP,
+        SnowflakeDataType::TimestampTz => Type::TIMESTAMPTZ,
+        SnowflakeDataType::Variant | SnowflakeDataType::Object | SnowflakeDataType::Array => {
+            Type::JSONB
+        }
+        // in GEOGRAPHY_OUTPUT_FORMAT, which may be GeoJSON but also WKT
+        SnowflakeDataType::Geography | SnowflakeDataType::Geometry => Type::TEXT,
+        SnowflakeDataType::Unknown => {
+            warn!(
+                "column {} has a type unknown to PeerDB, sending it as text",
+                col.name
+            );
+            Type::TEXT
+        }
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+#[error("Postgres type {0} has no lossless Snowflake equivalent")]
+pub struct UnsupportedPgTypeError(pub Type);
+
+/// Snowflake column type that a Postgres column of type `pg_type` is loaded into by `COPY INTO`.
+///
+/// The type alone carries no typmod, so `numeric` is treated as unbounded and gets the widest
+/// precision Snowflake has with 9 fractional digits, and timestamps keep Postgres' microseconds.
+/// Extension types are resolved by name, which is how `citext` ends up as `VARCHAR`.
+pub fn pg_type_to_snowflake(pg_type: &Type) -> Result<String, UnsupportedPgTypeError> {
+    match pg_type.kind() {
+        Kind::Array(element) => {
+            // a Snowflake ARRAY is untyped, but its elements must still survive the load
+            pg_type_to_snowflake(element)
+                .map(|_| "ARRAY".to_string())
+                .map_err(|_| UnsupportedPgTypeError(pg_type.clone()))
+        }
+        Kind::Domain(base) => pg_type_to_snowflake(base),
+        _ => {
+            let snowflake_type = match pg_type.name() {
+                "bool" => "BOOLEAN",
+                "int2" => "NUMBER(5,0)",
+                "int4" | "oid" => "NUMBER(10,0)",
+                "int8" => "NUMBER(19,0)",
+                "numeric" => "NUMBER(38,9)",
+                "float4" | "float8" => "FLOAT",
+                "text" | "varchar" | "bpchar" | "char" | "name" | "citext" | "uuid" => "VARCHAR",
+                "bytea" => "BINARY",
+                "date" => "DATE",
+                "time" => "TIME(6)",
+                "timestamp" => "TIMESTAMP_NTZ(6)",
+                "timestamptz" => "TIMESTAMP_TZ(6)",
+                "json" | "jsonb" => "VARIANT",
+                // timetz, interval, money, geometric types, ...
+                _ => return Err(UnsupportedPgTypeError(pg_type.clone())),
+            };
+            Ok(snowflake_type.to_string())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn column(r#type: &str, scale: Option<u32>) -> ColumnMeta {
+        let data_type = serde_json::from_value(serde_json::Value::from(r#type)).unwrap();
+        ColumnMeta {
+            name: "c".to_string(),
+            data_type,
+            nullable: true,
+            precision: None,
+            scale,
+        }
+    }
+
+    #[test]
+    fn maps_snowflake_types_to_postgres() {
+        let cases = [
+            ("fixed", Some(0), Type::INT8),
+            ("fixed", None, Type::INT8),
+            ("fixed", Some(2), Type::NUMERIC),
+            ("real", None, Type::FLOAT8),
+            ("text", None, Type::TEXT),
+            ("binary", None, Type::BYTEA),
+            ("boolean", None, Type::BOOL),
+            ("date", None, Type::DATE),
+            ("time", Some(9), Type::TIME),
+            ("timestamp_ltz", Some(9), Type::TIMESTAMPTZ),
+            ("timestamp_ntz", Some(9), Type::TIMESTAMP),
+            ("timestamp_tz", Some(9), Type::TIMESTAMPTZ),
+            ("variant", None, Type::JSONB),
+            ("object", None, Type::JSONB),
+            ("array", None, Type::JSONB),
+            ("geography", None, Type::TEXT),
+            ("vector", None, Type::TEXT),
+        ];
+        for (r#type, scale, expected) in cases {
+            let col = column(r#type, scale);
+            assert_eq!(snowflake_type_to_pg_oid(&col), expected, "{type}");
+        }
+    }
+
+    #[test]
+    fn maps_integers_past_int8_to_numeric() {
+        for (precision, expected) in [(18, Type::INT8), (19, Type::NUMERIC), (38, Type::NUMERIC)] {
+            let col = ColumnMeta {
+                precision: Some(precision),
+                ..column("fixed", Some(0))
+            };
+            assert_eq!(snowflake_type_to_pg_oid(&col), expected, "{precision}");
+        }
+    }
+
+    #[test]
+    fn maps_postgres_types_to_snowflake() {
+        let citext = Type::new(
+            "citext".to_string(),
+            16385,
+            Kind::Simple,
+            "public".to_string(),
+        );
+        let cases = [
+            (Type::INT4, "NUMBER(10,0)"),
+            (Type::INT8, "NUMBER(19,0)"),
+            (Type::NUMERIC, "NUMBER(38,9)"),
+            (Type::FLOAT8, "FLOAT"),
+            (Type::TIMESTAMP, "TIMESTAMP_NTZ(6)"),
+            (Type::TIMESTAMPTZ, "TIMESTAMP_TZ(6)"),
+            (Type::JSONB, "VARIANT"),
+            (Type::BYTEA, "BINARY"),
+            (Type::UUID, "VARCHAR"),
+            (citext, "VARCHAR"),
+            (Type::INT4_ARRAY, "ARRAY"),
+            (Type::TEXT_ARRAY, "ARRAY"),
+        ];
+        for (pg_type, expected) in cases {
+            assert_eq!(
+                pg_type_to_snowflake(&pg_type).unwrap(),
+                expected,
+                "{pg_type}"
+            );
+        }
+    }
+
+    #[test]
+    fn rejects_postgres_types_without_snowflake_equivalent() {
+        for pg_type in [
+            Type::TIMETZ,
+            Type::INTERVAL,
+            Type::MONEY,
+            Type::INTERVAL_ARRAY,
+        ] {
+            assert!(pg_type_to_snowflake(&pg_type).is_err(), "{pg_type}");
+        }
+    }
+}
@@ -0,0 +1,150 @@
+use std::time::{Duration, Instant};
+
+use tracing::{info, warn};
+
+use crate::{SnowflakeClient, SnowflakeError};
+
+// warm-up is best-effort, so it shouldn't hold setup up for as long as a statement may
+const WARM_UP_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Hints for the errors a misconfigured peer runs into, keyed by Snowflake error code.
+const VALIDATION_HINTS: &[(&str, &str)] = &[
+    (
+        "390144",
+        "the JWT was rejected: check that the private key matches the public key set on the \
+         user (RSA_PUBLIC_KEY) and that the account identifier is correct",
+    ),
+    (
+        "390100",
+        "incorrect username or key: check that the user exists in this account",
+    ),
+    (
+        "390186",
+        "the role is not granted to the user: grant it or pick another role",
+    ),
+    (
+        "390422",
+        "a network policy blocks PeerDB's IP address: add it to the policy's allowed list",
+    ),
+    (
+        "000606",
+        "no usable warehouse: check that the warehouse exists and the role has USAGE on it",
+    ),
+    (
+        "002043",
+        "the database does not exist or the role has no access to it",
+    ),
+];
+
+fn validation_error(err: SnowflakeError) -> SnowflakeError {
+    let Some(api_error) = err.api_error() else {
+        return err;
+    };
+    let Some(&(_, hint)) = VALIDATION_HINTS
+        .iter()
+        .find(|(known, _)| *known == api_error.code)
+    else {
+        return err;
+    };
+    let failed = SnowflakeError::ValidationFailed {
+        code: api_error.code.clone(),
+        hint,
+        message: api_error.message.clone(),
+    };
+    match err.request_id() {
+        Some(request_id) => SnowflakeError::Statement {
+            request_id,
+            source: Box::new(failed),
+        },
+        None => failed,
+    }
+}
+
+impl SnowflakeClient {
+    /// Resolves the account host and opens a TLS connection to it with an unauthenticated
+    /// `HEAD`, leaving the connection in the pool so the first statement doesn't pay for the
+    /// handshake. Best-effort: a failure is logged, and left to the first statement to report.
+    #[tracing::instrument(name = "peer_sflake::warm_up", skip_all)]
+    pub async fn warm_up(&self) {
+        let started = Instant::now();
+        // any response will do, Snowflake doesn't allow HEAD on the endpoint anyway
+        let res = self
+            .http_client()
+            .head(self.endpoint_url())
+            .timeout(WARM_UP_TIMEOUT)
+            .send()
+            .await;
+        match res {
+            Ok(response) => info!(
+                "Connected to Snowflake in {:?}, got {}",
+                started.elapsed(),
+                response.status()
+            ),
+            Err(err) => warn!("Failed to warm up connection to Snowflake: {}", err),
+        }
+    }
+
+    /// Warms the connection up as [`SnowflakeClient::warm_up`] does, then runs `SELECT 1` to
+    /// check that Snowflake accepts the peer's credentials and that its role and warehouse are
+    /// usable, explaining the usual failures.
+    #[tracing::instrument(name = "peer_sflake::validate", skip_all)]
+    pub async fn validate(&self) -> Result<(), SnowflakeError> {
+        self.warm_up().await;
+        self.execute("SELECT 1")
+            .await
+            .map(|_| ())
+            .map_err(validation_error)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use reqwest::StatusCode;
+
+    use super::*;
+    use crate::mock::{MockSnowflakeServer, MockStatement};
+
+    #[tokio::test]
+    async fn warms_up_before_validating() {
+        let server = MockSnowflakeServer::start().await;
+        server.mount_statement(MockStatement::new("handle-1")).await;
+
+        server.client().validate().await.unwrap();
+        let methods: Vec<_> = server
+            .requests_to("/api/v2/statements")
+            .await
+            .iter()
+            .map(|request| request.method.to_string())
+            .collect();
+        assert_eq!(methods, ["HEAD", "POST"]);
+
+        // nothing listens there, which is only logged
+        let unreachable = server
+            .client()
+            .with_endpoint_url("http://127.0.0.1:9/api/v2/statements".to_string());
+        unreachable.warm_up().await;
+    }
+
+    #[test]
+    fn explains_known_error_codes() {
+        let err = validation_error(SnowflakeError::from_response(
+            StatusCode::UNAUTHORIZED,
+            r#"{"code": "390144", "message": "JWT token is invalid."}"#.to_string(),
+        ));
+        let SnowflakeError::ValidationFailed { code, message, .. } = &err else {
+            panic!("expected a validation error, got {err:?}");
+        };
+        assert_eq!(code, "390144");
+        assert_eq!(message, "JWT token is invalid.");
+        assert!(err.to_string().contains("RSA_PUBLIC_KEY"));
+    }
+
+    #[test]
+    fn keeps_other_errors() {
+        let err = validation_error(SnowflakeError::from_response(
+            StatusCode::BAD_GATEWAY,
+            "<html>bad gateway</html>".to_string(),
+        ));
+        assert!(matches!(err, SnowflakeError::ServiceUnavailable { .. }));
+    }
+}